@@ -1,45 +1,47 @@
-#![feature(read_exact)]
+extern crate transfer;
+extern crate mio;
+extern crate env_logger;
 
-extern crate http2parse;
-extern crate rand;
+use transfer::{rt, Request};
+use transfer::prelude::Async;
+use mio::{EventLoopConfig};
 
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::str::FromStr;
 use std::thread;
 
 fn main() {
-    let guards = (0..100).map(|_| { go() }).collect::<Vec<_>>();
-    for guard in guards { guard.join().unwrap(); }
-}
-
-#[cfg(not(feature = "random"))]
-fn go() -> thread::JoinHandle<()> {
-    panic!("Running client without features = random.");
-}
+    env_logger::init().unwrap();
 
-#[cfg(feature = "random")]
-fn go() -> thread::JoinHandle<()> {
-    use std::net::TcpStream;
-    use std::io::{Read, Write};
+    let metadata = rt::Metadata {
+        executor: Arc::new(Box::new(ThreadExecutor)),
+        settings: Default::default(),
+        keepalive: None,
+        padding: Default::default()
+    };
 
-    use http2parse::{Frame, FrameHeader};
+    let handle = rt::start(EventLoopConfig::new(), metadata).unwrap();
 
-    thread::spawn(move || {
-        for _ in 0..2 {
-            let mut stream = TcpStream::connect("localhost:3000").unwrap();
+    let client = handle.connect(SocketAddr::from_str("127.0.0.1:3000").unwrap())
+        .unwrap().await().unwrap();
 
-            let frame = ::rand::random::<Frame<'static>>();
-            // println!("Sending Frame: {:?}", frame);
+    // All requests are multiplexed over the one connection.
+    let responses = (0..100).map(|_| {
+        client.send_request(Request::new("GET", "/"))
+    }).collect::<Vec<_>>();
 
-            let mut buf = vec![0; frame.encoded_len()];
-            let frame_len = frame.encode(&mut buf);
+    for response in responses {
+        println!("Received {:?}", response.await().unwrap());
+    }
 
-            stream.write(&buf[..frame_len]).unwrap();
+    handle.shutdown().unwrap().await().unwrap();
+}
 
-            buf = vec![0; frame.encoded_len()];
-            stream.read_exact(&mut buf).unwrap();
+struct ThreadExecutor;
 
-            let returned_header = FrameHeader::parse(&buf[..9]).unwrap();
-            let returned = Frame::parse(returned_header, &buf[9..]).unwrap();
-            assert_eq!(frame, returned);
-        }
-    })
+impl rt::Executor for ThreadExecutor {
+    fn execute(&self, task: rt::Thunk<'static>) {
+        thread::spawn(move || task());
+    }
 }
@@ -2,7 +2,7 @@ extern crate transfer;
 extern crate mio;
 extern crate env_logger;
 
-use transfer::{rt, Handler};
+use transfer::{rt, Handler, Request, Response, Future, Error};
 use mio::{EventLoopConfig};
 use mio::tcp::TcpListener;
 
@@ -15,7 +15,10 @@ fn main() {
     env_logger::init().unwrap();
 
     let metadata = rt::Metadata {
-        executor: Arc::new(Box::new(ThreadExecutor))
+        executor: Arc::new(Box::new(ThreadExecutor)),
+        settings: Default::default(),
+        keepalive: None,
+        padding: Default::default()
     };
 
     let handle = rt::start(EventLoopConfig::new(), metadata).unwrap();
@@ -40,6 +43,8 @@ impl rt::Executor for ThreadExecutor {
 struct NoopHandler;
 
 impl Handler for NoopHandler {
-    fn handle(&self) {}
+    fn handle(&self, _: Request) -> Future<Response, Error> {
+        Future::of(Response::new(200))
+    }
 }
 
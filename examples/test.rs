@@ -7,7 +7,7 @@ extern crate mio;
 extern crate env_logger;
 extern crate eventual;
 
-use transfer::{rt, Handler};
+use transfer::{rt, Handler, Request, Response, Future, Error};
 use mio::{EventLoopConfig};
 use mio::tcp::TcpListener;
 use eventual::Async;
@@ -21,7 +21,10 @@ fn main() {
     env_logger::init().unwrap();
 
     let metadata = rt::Metadata {
-        executor: Arc::new(Box::new(ThreadExecutor))
+        executor: Arc::new(Box::new(ThreadExecutor)),
+        settings: Default::default(),
+        keepalive: None,
+        padding: Default::default()
     };
 
     let handle = rt::start(EventLoopConfig::new(), metadata).unwrap();
@@ -49,7 +52,9 @@ impl rt::Executor for ThreadExecutor {
 struct NoopHandler;
 
 impl Handler for NoopHandler {
-    fn handle(&self) {}
+    fn handle(&self, _: Request) -> Future<Response, Error> {
+        Future::of(Response::new(200))
+    }
 }
 
 #[cfg(not(feature = "random"))]
@@ -0,0 +1,84 @@
+use http::parser::{Frame, Payload, Flag, StreamIdentifier};
+use http::error::{Error, Result, PROTOCOL_ERROR, ENHANCE_YOUR_CALM};
+
+use util;
+
+/// The largest header block we are willing to buffer across CONTINUATION frames.
+pub const MAX_HEADER_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// A header block which was started by a HEADERS or PUSH_PROMISE frame
+/// without END_HEADERS, and is waiting for CONTINUATION frames.
+///
+/// Section 6.10 of the spec:
+///
+/// ```text
+/// Any number of CONTINUATION frames can be sent, as long as the preceding frame is on the
+/// same stream and is a HEADERS, PUSH_PROMISE, or CONTINUATION frame without the
+/// END_HEADERS flag set.
+/// ```
+#[derive(Debug)]
+pub struct HeaderBlock {
+    frame: Frame,
+    fragments: Vec<u8>
+}
+
+impl HeaderBlock {
+    /// Start buffering the header block from a HEADERS or PUSH_PROMISE frame.
+    pub fn new(frame: Frame) -> HeaderBlock {
+        let fragments = match frame.payload {
+            Payload::Headers { ref block, .. } => block.to_vec(),
+            Payload::PushPromise { ref block, .. } => block.to_vec(),
+            ref payload => panic!("Header block started with {:?}", payload)
+        };
+
+        HeaderBlock {
+            frame: frame,
+            fragments: fragments
+        }
+    }
+
+    /// The stream which the CONTINUATION frames must be sent on.
+    pub fn id(&self) -> StreamIdentifier { self.frame.header.id }
+
+    /// Append the fragment from a CONTINUATION frame.
+    ///
+    /// Returns true if the frame ended the header block.
+    pub fn push(&mut self, continuation: Frame) -> Result<bool> {
+        if continuation.header.id != self.id() {
+            return Err(Error::connection(PROTOCOL_ERROR,
+                                         "CONTINUATION frame on a different stream"))
+        }
+
+        match continuation.payload {
+            Payload::Continuation(ref fragment) =>
+                self.fragments.extend(fragment.iter().cloned()),
+            _ => return Err(Error::connection(PROTOCOL_ERROR, "expected CONTINUATION frame"))
+        }
+
+        if self.fragments.len() > MAX_HEADER_BLOCK_SIZE {
+            return Err(Error::connection(ENHANCE_YOUR_CALM, "header block too large"))
+        }
+
+        Ok(continuation.header.flag.contains(Flag::end_headers()))
+    }
+
+    /// The original frame, with the complete header block and END_HEADERS set.
+    pub fn finish(self) -> Frame {
+        let block = util::slice(&self.fragments);
+
+        // Padding was already removed from the original frame's fragment.
+        let mut flag = self.frame.header.flag;
+        flag.insert(Flag::end_headers());
+        flag.remove(Flag::padded());
+
+        let payload = match self.frame.payload {
+            Payload::Headers { priority, .. } =>
+                Payload::Headers { priority: priority, block: block },
+            Payload::PushPromise { promised, .. } =>
+                Payload::PushPromise { promised: promised, block: block },
+            _ => unreachable!()
+        };
+
+        Frame::new(flag, self.frame.header.id, payload)
+    }
+}
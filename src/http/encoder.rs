@@ -1,6 +1,6 @@
 use appendbuf::Slice;
 
-use http::parser::{Frame, Priority, Payload, FrameHeader};
+use http::parser::{Frame, Priority, Payload, FrameHeader, Flag};
 use std::io;
 
 pub trait Encoder {
@@ -41,23 +41,57 @@ macro_rules! try_encode {
 #[derive(Debug, Clone)]
 pub struct FrameEncoder {
     header: FrameHeaderEncoder,
-    payload: PayloadEncoder
+    pad_length: Option<U8Encoder>,
+    payload: PayloadEncoder,
+    padding: Option<PaddingEncoder>
 }
 
 impl From<Frame> for FrameEncoder {
     fn from(frame: Frame) -> FrameEncoder {
         FrameEncoder {
             header: FrameHeaderEncoder::from(frame.header),
-            payload: PayloadEncoder::from(frame.payload)
+            pad_length: None,
+            payload: PayloadEncoder::from(frame.payload),
+            padding: None
         }
     }
 }
 
+impl FrameEncoder {
+    /// Encode a frame with `padding` bytes of padding, as described in
+    /// Section 6.1 of the spec.
+    ///
+    /// Only DATA, HEADERS and PUSH_PROMISE frames can be padded, other
+    /// frames are encoded without padding.
+    pub fn padded(mut frame: Frame, padding: u8) -> FrameEncoder {
+        match frame.payload {
+            Payload::Data(_) | Payload::Headers { .. } | Payload::PushPromise { .. } => {},
+            _ => return FrameEncoder::from(frame)
+        }
+
+        frame.header.length += 1 + padding as u32;
+        frame.header.flag.insert(Flag::padded());
+
+        let mut encoder = FrameEncoder::from(frame);
+        encoder.pad_length = Some(U8Encoder { buffer: [padding], position: 0 });
+        encoder.padding = Some(PaddingEncoder { remaining: padding as usize });
+        encoder
+    }
+}
+
 impl Encoder for FrameEncoder {
     fn encode<W: io::Write>(&mut self, write: &mut W) -> EncodeResult {
         let n = try_encode!(self.header.encode(write), 0);
-        let m = try_encode!(self.payload.encode(write), n);
-        EncodeResult::from_bytes(n + m)
+        let p = match self.pad_length {
+            Some(ref mut pad_length) => try_encode!(pad_length.encode(write), n),
+            None => 0
+        };
+        let m = try_encode!(self.payload.encode(write), n + p);
+        let z = match self.padding {
+            Some(ref mut padding) => try_encode!(padding.encode(write), n + p + m),
+            None => 0
+        };
+        EncodeResult::from_bytes(n + p + m + z)
     }
 }
 
@@ -114,7 +148,8 @@ impl From<Payload> for PayloadEncoder {
             Payload::WindowUpdate(increment) =>
                 WindowUpdate(U32Encoder::from(increment.0)),
             Payload::Continuation(block) => Continuation(SliceEncoder::from(block)),
-            Payload::Unregistered(block) => Unregistered(SliceEncoder::from(block))
+            Payload::PriorityUpdate(block) | Payload::Unregistered(block) =>
+                Unregistered(SliceEncoder::from(block))
         }
     }
 }
@@ -150,6 +185,31 @@ impl Encoder for PayloadEncoder {
     }
 }
 
+// Padding is never longer than this, as its length is a single byte.
+static ZEROES: [u8; 255] = [0; 255];
+
+#[derive(Debug, Clone)]
+struct PaddingEncoder {
+    remaining: usize
+}
+
+impl Encoder for PaddingEncoder {
+    fn encode<W: io::Write>(&mut self, write: &mut W) -> EncodeResult {
+        if self.remaining == 0 { return EncodeResult::Finished }
+
+        match write.write(&ZEROES[..self.remaining]) {
+            Ok(0) => EncodeResult::Eof,
+            Ok(n) => {
+                self.remaining -= n;
+                EncodeResult::Wrote(n)
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>
+                EncodeResult::WouldBlock(0),
+            Err(e) => EncodeResult::Error(e)
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct SliceEncoder {
     slice: Slice,
@@ -277,6 +337,7 @@ small_buffer_encoder! { FrameHeaderEncoder, 9 }
 small_buffer_encoder! { PriorityEncoder, 5 }
 small_buffer_encoder! { U64Encoder, 8 }
 small_buffer_encoder! { U32Encoder, 4 }
+small_buffer_encoder! { U8Encoder, 1 }
 
 #[cfg(test)]
 mod test {
@@ -350,6 +411,29 @@ mod test {
         assert_eq!(&*result, &*abuf);
     }
 
+    #[test]
+    fn test_padded_frame_encoder() {
+        use http::encoder::FrameEncoder;
+        use http::parser::{Frame, Payload, Flag, StreamIdentifier};
+        use util;
+
+        let frame = Frame::new(Flag::end_stream(), StreamIdentifier(1),
+                               Payload::Data(util::slice(b"hello")));
+        let mut encoder = FrameEncoder::padded(frame, 3);
+
+        let mut result = Vec::new();
+        loop {
+            match encoder.encode(&mut result) {
+                EncodeResult::Wrote(_) => continue,
+                EncodeResult::Finished => break,
+                e => panic!("Bad encode result {:?}", e)
+            }
+        }
+
+        assert_eq!(&*result, &[0, 0, 9, 0, 0x9, 0, 0, 0, 1,
+                               3, b'h', b'e', b'l', b'l', b'o', 0, 0, 0][..]);
+    }
+
     #[test]
     fn test_small_buffer_encoder() {
         small_buffer_encoder! { TestEncoder, 8 }
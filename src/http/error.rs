@@ -1,16 +1,63 @@
 use std::error::Error as StdError;
 use std::fmt;
 
+use http::parser::{ErrorCode, StreamIdentifier};
+
 pub type Result<T> = ::std::result::Result<T, Error>;
 
-#[derive(Debug)]
-pub enum Error {
-    InvalidFrameTypeForStreamState
+/// An HTTP/2 protocol error, as described in Section 5.4 of the spec.
+///
+/// Connection errors are answered with GOAWAY and the connection is closed,
+/// stream errors only cause the stream to be reset with RST_STREAM.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub scope: Scope,
+
+    /// The error code to send to the peer.
+    pub code: ErrorCode,
+
+    /// A description of what went wrong, for debugging.
+    pub message: &'static str
+}
+
+/// Whether an Error affects the whole connection or a single stream.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Scope {
+    Connection,
+    Stream(StreamIdentifier)
+}
+
+impl Error {
+    /// A connection error with the given code.
+    pub fn connection(code: ErrorCode, message: &'static str) -> Error {
+        Error { scope: Scope::Connection, code: code, message: message }
+    }
+
+    /// A stream error on the given stream with the given code.
+    pub fn stream(id: StreamIdentifier, code: ErrorCode, message: &'static str) -> Error {
+        Error { scope: Scope::Stream(id), code: code, message: message }
+    }
+
+    /// Limit this error to the given stream.
+    pub fn on_stream(self, id: StreamIdentifier) -> Error {
+        Error { scope: Scope::Stream(id), ..self }
+    }
+
+    /// The error code to send to the peer for this error.
+    pub fn code(&self) -> ErrorCode { self.code }
+
+    /// The stream this error affects, if it is a stream error.
+    pub fn stream_id(&self) -> Option<StreamIdentifier> {
+        match self.scope {
+            Scope::Stream(id) => Some(id),
+            Scope::Connection => None
+        }
+    }
 }
 
 impl StdError for Error {
     fn description(&self) -> &str {
-        "Transfer HTTP2 Error"
+        self.message
     }
 
     fn cause(&self) -> Option<&StdError> {
@@ -20,7 +67,27 @@ impl StdError for Error {
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Debug::fmt(self, f)
+        match self.scope {
+            Scope::Connection =>
+                write!(f, "Connection error {:?}: {}", self.code, self.message),
+            Scope::Stream(id) =>
+                write!(f, "Stream error {:?} on {:?}: {}", self.code, id, self.message)
+        }
     }
 }
 
+// Error codes, as defined in Section 7 of the spec.
+pub const NO_ERROR: ErrorCode = ErrorCode(0x0);
+pub const PROTOCOL_ERROR: ErrorCode = ErrorCode(0x1);
+pub const INTERNAL_ERROR: ErrorCode = ErrorCode(0x2);
+pub const FLOW_CONTROL_ERROR: ErrorCode = ErrorCode(0x3);
+pub const SETTINGS_TIMEOUT: ErrorCode = ErrorCode(0x4);
+pub const STREAM_CLOSED: ErrorCode = ErrorCode(0x5);
+pub const FRAME_SIZE_ERROR: ErrorCode = ErrorCode(0x6);
+pub const REFUSED_STREAM: ErrorCode = ErrorCode(0x7);
+pub const CANCEL: ErrorCode = ErrorCode(0x8);
+pub const COMPRESSION_ERROR: ErrorCode = ErrorCode(0x9);
+pub const CONNECT_ERROR: ErrorCode = ErrorCode(0xa);
+pub const ENHANCE_YOUR_CALM: ErrorCode = ErrorCode(0xb);
+pub const INADEQUATE_SECURITY: ErrorCode = ErrorCode(0xc);
+pub const HTTP_1_1_REQUIRED: ErrorCode = ErrorCode(0xd);
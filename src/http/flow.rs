@@ -0,0 +1,54 @@
+use http::error::{Error, Result, FLOW_CONTROL_ERROR};
+use http::settings::MAX_WINDOW_SIZE;
+
+/// The initial size of the connection flow control windows, which is not
+/// affected by SETTINGS_INITIAL_WINDOW_SIZE.
+pub const DEFAULT_WINDOW_SIZE: u32 = 65535;
+
+/// A flow control window, as described in Section 6.9.1 of the spec.
+///
+/// Windows may become negative when SETTINGS_INITIAL_WINDOW_SIZE is
+/// reduced, but can never exceed 2^31-1.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Window(i64);
+
+impl Default for Window {
+    fn default() -> Window { Window::new(DEFAULT_WINDOW_SIZE) }
+}
+
+impl Window {
+    pub fn new(size: u32) -> Window { Window(size as i64) }
+
+    /// The number of bytes which may currently be sent.
+    pub fn available(&self) -> usize {
+        if self.0 < 0 { 0 } else { self.0 as usize }
+    }
+
+    /// Grow the window, as a result of a WINDOW_UPDATE.
+    pub fn increase(&mut self, increment: u32) -> Result<()> {
+        self.adjust(increment as i64)
+    }
+
+    /// Shrink the window by the length of a flow controlled frame.
+    ///
+    /// It is an error for the frame to be larger than the window.
+    pub fn consume(&mut self, length: usize) -> Result<()> {
+        if length as i64 > self.0 {
+            return Err(Error::connection(FLOW_CONTROL_ERROR, "flow control window exceeded"))
+        }
+
+        self.0 -= length as i64;
+        Ok(())
+    }
+
+    /// Change the window by the difference between an old and new
+    /// SETTINGS_INITIAL_WINDOW_SIZE.
+    pub fn adjust(&mut self, delta: i64) -> Result<()> {
+        if self.0 + delta > MAX_WINDOW_SIZE as i64 {
+            return Err(Error::connection(FLOW_CONTROL_ERROR, "flow control window overflow"))
+        }
+
+        self.0 += delta;
+        Ok(())
+    }
+}
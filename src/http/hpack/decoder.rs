@@ -0,0 +1,348 @@
+use std::fmt;
+
+use super::table::Table;
+use super::huffman;
+use super::{HeaderField, Error, Result};
+
+/// The default maximum size of the dynamic table.
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+/// An HPACK decoder, holding the dynamic table for one direction of
+/// a connection.
+#[derive(Clone)]
+pub struct Decoder {
+    table: Table,
+    huffman: huffman::Decoder,
+
+    // The largest table size the peer's encoder may choose, which is the
+    // value of SETTINGS_HEADER_TABLE_SIZE we have advertised.
+    max_size: usize
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            table: Table::new(DEFAULT_TABLE_SIZE),
+            huffman: huffman::Decoder::new(),
+            max_size: DEFAULT_TABLE_SIZE
+        }
+    }
+
+    /// Set the largest dynamic table size the encoder may use.
+    ///
+    /// This should follow the SETTINGS_HEADER_TABLE_SIZE we advertise,
+    /// once it has been acknowledged.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+
+        if self.table.max_size() > max_size {
+            self.table.set_max_size(max_size);
+        }
+    }
+
+    /// The current size of the dynamic table.
+    pub fn table_size(&self) -> usize { self.table.size() }
+
+    /// Decode a complete header block into a header list.
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<HeaderField>> {
+        let mut headers = Vec::new();
+        let mut position = 0;
+
+        // Dynamic table size updates may only appear at the start of a block.
+        let mut start = true;
+
+        while position < block.len() {
+            let rest = &block[position..];
+            let byte = rest[0];
+
+            if byte & 0x80 == 0x80 {
+                // Indexed Header Field, Section 6.1.
+                let (index, n) = try!(integer(rest, 7));
+                let (name, value) = try!(self.entry(index));
+
+                headers.push(HeaderField::new(name, value));
+                position += n;
+            } else if byte & 0x40 == 0x40 {
+                // Literal Header Field with Incremental Indexing, Section 6.2.1.
+                let (field, n) = try!(self.literal(rest, 6));
+
+                self.table.insert(field.clone());
+                headers.push(field);
+                position += n;
+            } else if byte & 0x20 == 0x20 {
+                // Dynamic Table Size Update, Section 6.3.
+                let (size, n) = try!(integer(rest, 5));
+                if !start || size > self.max_size {
+                    return Err(Error::InvalidTableSizeUpdate)
+                }
+
+                self.table.set_max_size(size);
+                position += n;
+                continue
+            } else {
+                // Literal Header Field without Indexing or Never Indexed,
+                // Sections 6.2.2 and 6.2.3.
+                let (mut field, n) = try!(self.literal(rest, 4));
+                field.sensitive = byte & 0x10 == 0x10;
+
+                headers.push(field);
+                position += n;
+            }
+
+            start = false;
+        }
+
+        Ok(headers)
+    }
+
+    fn entry(&self, index: usize) -> Result<(Vec<u8>, Vec<u8>)> {
+        self.table.get(index)
+            .map(|(name, value)| (name.to_vec(), value.to_vec()))
+            .ok_or(Error::InvalidIndex(index))
+    }
+
+    fn literal(&self, buf: &[u8], prefix: u8) -> Result<(HeaderField, usize)> {
+        let (index, mut position) = try!(integer(buf, prefix));
+
+        let name = if index == 0 {
+            let (name, n) = try!(self.string(&buf[position..]));
+            position += n;
+            name
+        } else {
+            try!(self.entry(index)).0
+        };
+
+        let (value, n) = try!(self.string(&buf[position..]));
+        Ok((HeaderField::new(name, value), position + n))
+    }
+
+    fn string(&self, buf: &[u8]) -> Result<(Vec<u8>, usize)> {
+        let (length, n) = try!(integer(buf, 7));
+        if buf.len() - n < length {
+            return Err(Error::Truncated)
+        }
+
+        let raw = &buf[n..n + length];
+        let string = if buf[0] & 0x80 == 0x80 {
+            try!(self.huffman.decode(raw))
+        } else {
+            raw.to_vec()
+        };
+
+        Ok((string, n + length))
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder { Decoder::new() }
+}
+
+impl fmt::Debug for Decoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "hpack::Decoder {{ table: {:?}, max_size: {:?} }}",
+               self.table, self.max_size)
+    }
+}
+
+/// Decode an integer with an N-bit prefix, as described in Section 5.1.
+///
+/// Returns the integer and the number of bytes it occupied.
+pub fn integer(buf: &[u8], prefix: u8) -> Result<(usize, usize)> {
+    if buf.is_empty() {
+        return Err(Error::Truncated)
+    }
+
+    let max_prefix = ((1u16 << prefix) - 1) as u8;
+    let mut value = (buf[0] & max_prefix) as u64;
+    if value < max_prefix as u64 {
+        return Ok((value as usize, 1))
+    }
+
+    let mut shift = 0;
+    for (i, &byte) in buf[1..].iter().enumerate() {
+        // Five continuation bytes hold more than 32 bits, so any further
+        // bytes, even zeros, can only overflow.
+        if shift > 28 {
+            return Err(Error::IntegerOverflow)
+        }
+
+        value += ((byte & 0x7f) as u64) << shift;
+
+        if value > ::std::u32::MAX as u64 {
+            return Err(Error::IntegerOverflow)
+        }
+
+        if byte & 0x80 == 0 {
+            return Ok((value as usize, i + 2))
+        }
+
+        shift += 7;
+    }
+
+    Err(Error::Truncated)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Decoder, integer};
+    use http::hpack::{HeaderField, Error};
+    use http::hpack::test::hex;
+
+    fn check(decoder: &mut Decoder, block: &str, expected: &[(&str, &str)], size: usize) {
+        let headers = decoder.decode(&hex(block)).unwrap();
+        let headers = headers.iter()
+            .map(|field| (String::from_utf8(field.name.clone()).unwrap(),
+                          String::from_utf8(field.value.clone()).unwrap()))
+            .collect::<Vec<_>>();
+        let expected = expected.iter()
+            .map(|&(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(headers, expected);
+        assert_eq!(decoder.table_size(), size);
+    }
+
+    #[test]
+    fn test_integer() {
+        // C.1.1 - C.1.3
+        assert_eq!(integer(&[0x0a], 5), Ok((10, 1)));
+        assert_eq!(integer(&[0x1f, 0x9a, 0x0a], 5), Ok((1337, 3)));
+        assert_eq!(integer(&[0x2a], 8), Ok((42, 1)));
+
+        assert_eq!(integer(&[0x1f, 0x9a], 5), Err(Error::Truncated));
+        assert_eq!(integer(&[0x1f, 0xff, 0xff, 0xff, 0xff, 0x7f], 5),
+                   Err(Error::IntegerOverflow));
+
+        // Zero continuation bytes add nothing but still move the shift.
+        let mut zeros = vec![0x1f];
+        zeros.extend(vec![0x80; 12]);
+        zeros.push(0x01);
+        assert_eq!(integer(&zeros, 5), Err(Error::IntegerOverflow));
+    }
+
+    #[test]
+    fn test_literal_fields() {
+        // C.2.1
+        check(&mut Decoder::new(),
+              "400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572",
+              &[("custom-key", "custom-header")], 55);
+
+        // C.2.2
+        check(&mut Decoder::new(), "040c 2f73 616d 706c 652f 7061 7468",
+              &[(":path", "/sample/path")], 0);
+
+        // C.2.3
+        let mut decoder = Decoder::new();
+        let headers = decoder.decode(
+            &hex("1008 7061 7373 776f 7264 0673 6563 7265 74")).unwrap();
+        assert_eq!(headers, vec![HeaderField {
+            name: b"password".to_vec(),
+            value: b"secret".to_vec(),
+            sensitive: true
+        }]);
+        assert_eq!(decoder.table_size(), 0);
+
+        // C.2.4
+        check(&mut Decoder::new(), "82", &[(":method", "GET")], 0);
+    }
+
+    #[test]
+    fn test_requests() {
+        // C.3
+        let mut decoder = Decoder::new();
+        check(&mut decoder, "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+              &[(":method", "GET"), (":scheme", "http"), (":path", "/"),
+                (":authority", "www.example.com")], 57);
+        check(&mut decoder, "8286 84be 5808 6e6f 2d63 6163 6865",
+              &[(":method", "GET"), (":scheme", "http"), (":path", "/"),
+                (":authority", "www.example.com"), ("cache-control", "no-cache")], 110);
+        check(&mut decoder,
+              "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+              &[(":method", "GET"), (":scheme", "https"), (":path", "/index.html"),
+                (":authority", "www.example.com"), ("custom-key", "custom-value")], 164);
+    }
+
+    #[test]
+    fn test_requests_huffman() {
+        // C.4
+        let mut decoder = Decoder::new();
+        check(&mut decoder, "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+              &[(":method", "GET"), (":scheme", "http"), (":path", "/"),
+                (":authority", "www.example.com")], 57);
+        check(&mut decoder, "8286 84be 5886 a8eb 1064 9cbf",
+              &[(":method", "GET"), (":scheme", "http"), (":path", "/"),
+                (":authority", "www.example.com"), ("cache-control", "no-cache")], 110);
+        check(&mut decoder,
+              "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+              &[(":method", "GET"), (":scheme", "https"), (":path", "/index.html"),
+                (":authority", "www.example.com"), ("custom-key", "custom-value")], 164);
+    }
+
+    #[test]
+    fn test_responses() {
+        // C.5
+        let mut decoder = Decoder::new();
+        decoder.set_max_size(256);
+
+        check(&mut decoder,
+              "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 \
+               3230 3133 2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 \
+               7777 2e65 7861 6d70 6c65 2e63 6f6d",
+              &[(":status", "302"), ("cache-control", "private"),
+                ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+                ("location", "https://www.example.com")], 222);
+        check(&mut decoder, "4803 3330 37c1 c0bf",
+              &[(":status", "307"), ("cache-control", "private"),
+                ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+                ("location", "https://www.example.com")], 222);
+        check(&mut decoder,
+              "88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 \
+               3220 474d 54c0 5a04 677a 6970 7738 666f 6f3d 4153 444a 4b48 514b 425a \
+               584f 5157 454f 5049 5541 5851 5745 4f49 553b 206d 6178 2d61 6765 3d33 \
+               3630 303b 2076 6572 7369 6f6e 3d31",
+              &[(":status", "200"), ("cache-control", "private"),
+                ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+                ("location", "https://www.example.com"), ("content-encoding", "gzip"),
+                ("set-cookie", "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1")],
+              215);
+    }
+
+    #[test]
+    fn test_responses_huffman() {
+        // C.6
+        let mut decoder = Decoder::new();
+        decoder.set_max_size(256);
+
+        check(&mut decoder,
+              "4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005 9504 0b81 \
+               66e0 82a6 2d1b ff6e 919d 29ad 1718 63c7 8f0b 97c8 e9ae 82ae 43d3",
+              &[(":status", "302"), ("cache-control", "private"),
+                ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+                ("location", "https://www.example.com")], 222);
+        check(&mut decoder, "4883 640e ffc1 c0bf",
+              &[(":status", "307"), ("cache-control", "private"),
+                ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+                ("location", "https://www.example.com")], 222);
+        check(&mut decoder,
+              "88c1 6196 d07a be94 1054 d444 a820 0595 040b 8166 e084 a62d 1bff c05a \
+               839b d9ab 77ad 94e7 821d d7f2 e6c7 b335 dfdf cd5b 3960 d5af 2708 7f36 \
+               72c1 ab27 0fb5 291f 9587 3160 65c0 03ed 4ee5 b106 3d50 07",
+              &[(":status", "200"), ("cache-control", "private"),
+                ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+                ("location", "https://www.example.com"), ("content-encoding", "gzip"),
+                ("set-cookie", "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1")],
+              215);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.decode(&hex("80")), Err(Error::InvalidIndex(0)));
+        assert_eq!(decoder.decode(&hex("be")), Err(Error::InvalidIndex(62)));
+        assert_eq!(decoder.decode(&hex("400a 6375")), Err(Error::Truncated));
+
+        // Size updates must come first and respect the advertised maximum.
+        assert_eq!(decoder.decode(&hex("82 20")), Err(Error::InvalidTableSizeUpdate));
+        assert_eq!(decoder.decode(&hex("3fe2 1f")), Err(Error::InvalidTableSizeUpdate));
+    }
+}
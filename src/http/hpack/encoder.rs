@@ -0,0 +1,281 @@
+use std::cmp;
+
+use super::table::Table;
+use super::huffman;
+use super::HeaderField;
+
+use super::decoder::DEFAULT_TABLE_SIZE;
+
+/// An HPACK encoder, holding the dynamic table for one direction of
+/// a connection.
+///
+/// Each field is encoded as an index when it is already in a table,
+/// as a never indexed literal when it is sensitive, and as a literal
+/// with incremental indexing otherwise. Strings are Huffman encoded
+/// when that makes them shorter.
+#[derive(Debug, Clone)]
+pub struct Encoder {
+    table: Table,
+
+    // The table size we would like to use.
+    table_size: usize,
+
+    // The largest table size the peer's decoder allows, which is the
+    // value of SETTINGS_HEADER_TABLE_SIZE the peer has advertised.
+    max_size: usize,
+
+    // The smallest table size used since the last block, which must be
+    // signalled to the decoder before the final size.
+    update: Option<usize>
+}
+
+impl Default for Encoder {
+    fn default() -> Encoder { Encoder::new() }
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder::with_table_size(DEFAULT_TABLE_SIZE)
+    }
+
+    /// Create an Encoder which will use a dynamic table of at most
+    /// `table_size` bytes.
+    pub fn with_table_size(table_size: usize) -> Encoder {
+        let mut encoder = Encoder {
+            table: Table::new(DEFAULT_TABLE_SIZE),
+            table_size: table_size,
+            max_size: DEFAULT_TABLE_SIZE,
+            update: None
+        };
+
+        encoder.resize();
+        encoder
+    }
+
+    /// Change the size of the dynamic table we would like to use.
+    pub fn set_table_size(&mut self, table_size: usize) {
+        self.table_size = table_size;
+        self.resize();
+    }
+
+    /// Set the largest dynamic table size the peer's decoder allows.
+    ///
+    /// This should follow the SETTINGS_HEADER_TABLE_SIZE advertised
+    /// by the peer.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.resize();
+    }
+
+    /// The current size of the dynamic table.
+    pub fn table_size(&self) -> usize { self.table.size() }
+
+    /// Encode a header list into a complete header block.
+    pub fn encode(&mut self, headers: &[HeaderField]) -> Vec<u8> {
+        let mut block = Vec::new();
+
+        if let Some(smallest) = self.update.take() {
+            let size = self.table.max_size();
+            if smallest < size {
+                integer(smallest, 5, 0x20, &mut block);
+            }
+
+            integer(size, 5, 0x20, &mut block);
+        }
+
+        for field in headers {
+            self.field(field, &mut block);
+        }
+
+        block
+    }
+
+    fn field(&mut self, field: &HeaderField, block: &mut Vec<u8>) {
+        let found = self.table.find(&field.name, &field.value);
+        let sensitive = field.sensitive || is_sensitive(field);
+
+        match found {
+            Some((index, true)) if !sensitive => {
+                // Indexed Header Field.
+                integer(index, 7, 0x80, block);
+                return
+            },
+            _ => {}
+        }
+
+        let name = found.map(|(index, _)| index).unwrap_or(0);
+
+        if sensitive {
+            // Literal Header Field Never Indexed.
+            literal(name, 4, 0x10, field, block);
+        } else if field.size() > self.table.max_size() {
+            // Literal Header Field without Indexing, since it would only
+            // empty the dynamic table.
+            literal(name, 4, 0x00, field, block);
+        } else {
+            // Literal Header Field with Incremental Indexing.
+            literal(name, 6, 0x40, field, block);
+            self.table.insert(field.clone());
+        }
+    }
+
+    fn resize(&mut self) {
+        let size = cmp::min(self.table_size, self.max_size);
+
+        if size != self.table.max_size() {
+            let smallest = cmp::min(self.update.unwrap_or(size), size);
+            self.update = Some(cmp::min(smallest, self.table.max_size()));
+            self.table.set_max_size(size);
+        }
+    }
+}
+
+/// Fields which should never be indexed, as suggested in Section 7.1.3.
+fn is_sensitive(field: &HeaderField) -> bool {
+    match &*field.name {
+        b"authorization" | b"proxy-authorization" => true,
+        b"cookie" => field.value.len() < 20,
+        _ => false
+    }
+}
+
+fn literal(name: usize, prefix: u8, flags: u8, field: &HeaderField, block: &mut Vec<u8>) {
+    integer(name, prefix, flags, block);
+
+    if name == 0 {
+        string(&field.name, block);
+    }
+
+    string(&field.value, block);
+}
+
+fn string(string: &[u8], block: &mut Vec<u8>) {
+    let huffman_len = huffman::encoded_len(string);
+
+    if huffman_len < string.len() {
+        integer(huffman_len, 7, 0x80, block);
+        huffman::encode(string, block);
+    } else {
+        integer(string.len(), 7, 0x00, block);
+        block.extend(string.iter().cloned());
+    }
+}
+
+/// Encode an integer with an N-bit prefix, as described in Section 5.1.
+///
+/// `flags` are the bits of the first byte above the prefix.
+pub fn integer(mut value: usize, prefix: u8, flags: u8, block: &mut Vec<u8>) {
+    let max_prefix = ((1u16 << prefix) - 1) as usize;
+
+    if value < max_prefix {
+        block.push(flags | value as u8);
+        return
+    }
+
+    block.push(flags | max_prefix as u8);
+    value -= max_prefix;
+
+    while value >= 128 {
+        block.push((value % 128) as u8 | 0x80);
+        value /= 128;
+    }
+
+    block.push(value as u8);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Encoder, integer};
+    use http::hpack::{Decoder, HeaderField};
+    use http::hpack::test::hex;
+
+    fn fields(headers: &[(&str, &str)]) -> Vec<HeaderField> {
+        headers.iter().map(|&(name, value)| HeaderField::new(name, value)).collect()
+    }
+
+    #[test]
+    fn test_integer() {
+        // C.1.1 - C.1.3
+        let mut block = Vec::new();
+        integer(10, 5, 0, &mut block);
+        integer(1337, 5, 0, &mut block);
+        integer(42, 8, 0, &mut block);
+        assert_eq!(block, vec![0x0a, 0x1f, 0x9a, 0x0a, 0x2a]);
+    }
+
+    #[test]
+    fn test_requests_huffman() {
+        // C.4
+        let mut encoder = Encoder::new();
+        assert_eq!(encoder.encode(&fields(&[
+            (":method", "GET"), (":scheme", "http"), (":path", "/"),
+            (":authority", "www.example.com")
+        ])), hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"));
+        assert_eq!(encoder.table_size(), 57);
+
+        assert_eq!(encoder.encode(&fields(&[
+            (":method", "GET"), (":scheme", "http"), (":path", "/"),
+            (":authority", "www.example.com"), ("cache-control", "no-cache")
+        ])), hex("8286 84be 5886 a8eb 1064 9cbf"));
+        assert_eq!(encoder.table_size(), 110);
+
+        assert_eq!(encoder.encode(&fields(&[
+            (":method", "GET"), (":scheme", "https"), (":path", "/index.html"),
+            (":authority", "www.example.com"), ("custom-key", "custom-value")
+        ])), hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf"));
+        assert_eq!(encoder.table_size(), 164);
+    }
+
+    #[test]
+    fn test_responses_huffman() {
+        // C.6.1, with the dynamic table size signalled at the start.
+        let mut encoder = Encoder::with_table_size(256);
+        assert_eq!(encoder.encode(&fields(&[
+            (":status", "302"), ("cache-control", "private"),
+            ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+            ("location", "https://www.example.com")
+        ])), hex("3fe1 01 4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005 \
+                  9504 0b81 66e0 82a6 2d1b ff6e 919d 29ad 1718 63c7 8f0b 97c8 e9ae \
+                  82ae 43d3"));
+        assert_eq!(encoder.table_size(), 222);
+    }
+
+    #[test]
+    fn test_never_indexed() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+
+        let mut secret = HeaderField::new("x-secret", "hunter2");
+        secret.sensitive = true;
+        let headers = vec![HeaderField::new("authorization", "Basic Zm9vOmJhcg=="), secret];
+
+        let block = encoder.encode(&headers);
+        assert_eq!(encoder.table_size(), 0);
+
+        let decoded = decoder.decode(&block).unwrap();
+        assert!(decoded.iter().all(|field| field.sensitive));
+        assert_eq!(decoded[1], headers[1]);
+    }
+
+    #[test]
+    fn test_roundtrip_table_size_changes() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+
+        let headers = fields(&[
+            (":status", "200"), ("content-type", "text/plain"),
+            ("x-request-id", "e3b0c44298fc1c149afbf4c8996fb924"),
+            ("server", "transfer")
+        ]);
+
+        for size in &[4096, 0, 64, 1024] {
+            encoder.set_table_size(*size);
+
+            for _ in 0..3 {
+                let block = encoder.encode(&headers);
+                assert_eq!(decoder.decode(&block).unwrap(), headers);
+                assert_eq!(decoder.table_size(), encoder.table_size());
+            }
+        }
+    }
+}
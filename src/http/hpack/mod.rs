@@ -0,0 +1,91 @@
+//! HPACK header compression, as described in RFC 7541.
+
+use std::error::Error as StdError;
+use std::fmt;
+
+pub use self::decoder::Decoder;
+pub use self::encoder::Encoder;
+
+pub mod decoder;
+pub mod encoder;
+
+mod table;
+mod huffman;
+
+/// A single header field, as stored in a header list.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HeaderField {
+    pub name: Vec<u8>,
+    pub value: Vec<u8>,
+
+    /// Sensitive fields are never added to any dynamic table,
+    /// by us or by an intermediary.
+    pub sensitive: bool
+}
+
+impl HeaderField {
+    pub fn new<N, V>(name: N, value: V) -> HeaderField
+    where N: Into<Vec<u8>>, V: Into<Vec<u8>> {
+        HeaderField {
+            name: name.into(),
+            value: value.into(),
+            sensitive: false
+        }
+    }
+
+    /// The size of this field when stored in the dynamic table,
+    /// as defined in Section 4.1.
+    pub fn size(&self) -> usize {
+        self.name.len() + self.value.len() + 32
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// Errors encountered while decoding a header block.
+///
+/// All of these are connection errors of type COMPRESSION_ERROR.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The block ended in the middle of a representation.
+    Truncated,
+
+    /// An integer was too large to represent.
+    IntegerOverflow,
+
+    /// An index was not present in the static or dynamic tables.
+    InvalidIndex(usize),
+
+    /// A Huffman encoded string contained EOS or invalid padding.
+    InvalidHuffman,
+
+    /// A dynamic table size update was larger than allowed or was not
+    /// at the start of the block.
+    InvalidTableSizeUpdate
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        "Transfer HPACK Error"
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    /// Parse a hex dump, such as the examples in Appendix C, ignoring
+    /// whitespace.
+    pub fn hex(data: &str) -> Vec<u8> {
+        let digits = data.chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| c.to_digit(16).unwrap() as u8)
+            .collect::<Vec<_>>();
+
+        digits.chunks(2).map(|pair| pair[0] << 4 | pair[1]).collect()
+    }
+}
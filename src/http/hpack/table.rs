@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+
+use super::HeaderField;
+
+/// The static table from Appendix A of RFC 7541.
+///
+/// Entries are addressed starting at index 1.
+pub static STATIC_TABLE: [(&'static str, &'static str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", "")
+];
+
+/// The combined static and dynamic table, as described in Section 2.3.
+///
+/// Index 1 is the first entry of the static table, and the dynamic
+/// table starts immediately after the static table, with the most
+/// recently inserted entry first.
+#[derive(Debug, Clone)]
+pub struct Table {
+    dynamic: VecDeque<HeaderField>,
+    size: usize,
+    max_size: usize
+}
+
+impl Table {
+    pub fn new(max_size: usize) -> Table {
+        Table {
+            dynamic: VecDeque::new(),
+            size: 0,
+            max_size: max_size
+        }
+    }
+
+    /// Look up the entry at the given index.
+    pub fn get(&self, index: usize) -> Option<(&[u8], &[u8])> {
+        if index == 0 {
+            None
+        } else if index <= STATIC_TABLE.len() {
+            let (name, value) = STATIC_TABLE[index - 1];
+            Some((name.as_bytes(), value.as_bytes()))
+        } else {
+            self.dynamic.get(index - STATIC_TABLE.len() - 1)
+                .map(|field| (&*field.name, &*field.value))
+        }
+    }
+
+    /// Find the best entry for a header field.
+    ///
+    /// Returns the index of the entry and whether both the name and
+    /// value matched, or just the name.
+    pub fn find(&self, name: &[u8], value: &[u8]) -> Option<(usize, bool)> {
+        let statics = STATIC_TABLE.iter()
+            .map(|&(name, value)| (name.as_bytes(), value.as_bytes()));
+        let dynamics = self.dynamic.iter()
+            .map(|field| (&*field.name, &*field.value));
+
+        let mut found = None;
+
+        for (index, (entry_name, entry_value)) in statics.chain(dynamics).enumerate() {
+            if entry_name != name { continue }
+
+            if entry_value == value {
+                return Some((index + 1, true))
+            }
+
+            if found.is_none() {
+                found = Some((index + 1, false));
+            }
+        }
+
+        found
+    }
+
+    /// Insert an entry into the dynamic table, evicting older entries
+    /// to make room for it.
+    ///
+    /// An entry larger than the maximum size empties the table and is
+    /// not inserted.
+    pub fn insert(&mut self, field: HeaderField) {
+        let size = field.size();
+
+        if size > self.max_size {
+            self.dynamic.clear();
+            self.size = 0;
+            return
+        }
+
+        self.evict(self.max_size - size);
+        self.size += size;
+        self.dynamic.push_front(field);
+    }
+
+    /// Change the maximum size of the dynamic table, evicting entries
+    /// as needed.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.evict(max_size);
+    }
+
+    /// The current size of the dynamic table, as defined in Section 4.1.
+    pub fn size(&self) -> usize { self.size }
+
+    /// The maximum size of the dynamic table.
+    pub fn max_size(&self) -> usize { self.max_size }
+
+    /// The number of entries in the dynamic table.
+    pub fn len(&self) -> usize { self.dynamic.len() }
+
+    fn evict(&mut self, target: usize) {
+        while self.size > target {
+            let evicted = self.dynamic.pop_back()
+                .expect("Dynamic table size is non-zero but has no entries.");
+            self.size -= evicted.size();
+        }
+    }
+}
@@ -1,82 +1,1617 @@
-pub use self::stream::Stream;
-pub use self::error::{Error, Result};
+pub use self::stream::{Stream, State};
+pub use self::error::{Error, Result, Scope};
+pub use self::settings::Settings;
+pub use self::outgoing::{Outgoing, WriteCallback, Block};
+pub use self::padding::PaddingPolicy;
 
 pub mod parser;
 pub mod stream;
 pub mod error;
 pub mod encoder;
+pub mod settings;
+pub mod hpack;
+pub mod flow;
+pub mod priority;
+pub mod outgoing;
+pub mod validate;
+pub mod padding;
 
-use self::parser::{Frame, StreamIdentifier};
+mod block;
+
+use self::parser::{Frame, Payload, StreamIdentifier, ErrorCode, Flag, Kind, SizeIncrement};
+
+use byteorder::{ByteOrder, BigEndian};
+use appendbuf::Slice;
 use self::encoder::FrameEncoder;
+use self::block::HeaderBlock;
+use self::error::{PROTOCOL_ERROR, INTERNAL_ERROR, FRAME_SIZE_ERROR, COMPRESSION_ERROR,
+                  REFUSED_STREAM};
+use self::flow::{Window, DEFAULT_WINDOW_SIZE};
+use self::hpack::HeaderField;
+use self::priority::{Dependency, Urgency};
+
+use util;
 
-use std::collections::{VecDeque, HashMap};
+use std::collections::{VecDeque, HashMap, BTreeSet};
+use std::time::{Duration, Instant};
 use std::boxed::FnBox;
-use std::fmt;
+use std::{cmp, fmt, mem, usize};
 
 use eventual::Async;
 
+/// The connection preface which must be sent by clients before any frames.
+pub const PREFACE: &'static [u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The number of closed streams remembered, so that frames which arrive
+/// shortly after a stream closes are not mistaken for protocol errors.
+pub const CLOSED_STREAMS: usize = 128;
+
+/// The number of streams the peer has not yet opened whose priority is
+/// remembered. Priorities signalled for any further idle streams are
+/// ignored.
+pub const IDLE_STREAMS: usize = 128;
+
 #[derive(Debug, Default)]
 pub struct Http2 {
+    /// Which end of the connection we are.
+    pub role: Role,
+
     streams: HashMap<StreamIdentifier, Option<Stream>>,
-    pub outgoing: Outgoing
+
+    // The most recently closed streams, oldest first. Older closed streams
+    // are forgotten.
+    closed: VecDeque<StreamIdentifier>,
+
+    // The streams the peer has signalled a priority for but not yet opened,
+    // at most IDLE_STREAMS of them.
+    idle: BTreeSet<u32>,
+    pub outgoing: Outgoing,
+
+    /// Our settings, as acknowledged by the peer.
+    pub local: Settings,
+
+    /// The peer's settings.
+    pub remote: Settings,
+
+    // Settings we have sent but the peer has not yet acknowledged.
+    pending: VecDeque<Settings>,
+
+    // Decodes header blocks sent by the peer.
+    decoder: hpack::Decoder,
+
+    /// Encodes header blocks we send to the peer.
+    pub encoder: hpack::Encoder,
+
+    /// How the DATA and HEADERS frames we send are padded.
+    pub padding: PaddingPolicy,
+
+    /// The connection-level window for DATA we send.
+    pub send_window: Window,
+
+    /// The connection-level window for DATA the peer sends.
+    pub recv_window: Window,
+
+    // Space in `recv_window` freed by consumed DATA, which has not yet
+    // been returned to the peer.
+    unreleased: usize,
+
+    // A header block waiting for CONTINUATION frames. While this is
+    // set, no other frames may be received.
+    continuation: Option<HeaderBlock>,
+
+    // The highest stream id the peer has opened.
+    last_stream: u32,
+
+    // The id of the last stream we opened, or reserved for a push.
+    last_local: u32,
+
+    // The first frame sent by the peer must be a SETTINGS frame.
+    received_settings: bool,
+
+    // PINGs we have sent which the peer has not yet acknowledged, by
+    // their opaque data.
+    pings: HashMap<u64, (Instant, PingCallback)>,
+
+    // The opaque data of the next PING we send.
+    next_ping: u64,
+
+    // The last stream id sent in our GOAWAY, if we have sent one. Frames
+    // on later streams are ignored.
+    go_away: Option<u32>,
+
+    // Whether the peer has sent a GOAWAY, after which it opens no new
+    // streams.
+    received_go_away: bool,
+
+    /// Received headers, data and resets which the application has not yet
+    /// handled.
+    pub events: VecDeque<Event>
 }
 
-#[derive(Debug, Default)]
-pub struct Outgoing {
-    // TODO(reem): Replace with a priority/dependency tree.
-    queue: VecDeque<(Frame, WriteCallback)>,
-    pub current: Option<(FrameEncoder, WriteCallback)>
+/// Which end of a connection we are, which decides the streams each side
+/// may open, see Section 5.1.1 of the spec.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Role {
+    /// We accepted the connection, and the client opens streams with odd ids.
+    Server,
+
+    /// We opened the connection, and open streams with odd ids.
+    Client
+}
+
+impl Default for Role {
+    fn default() -> Role { Role::Server }
+}
+
+/// Something received from the peer which the application must act on.
+#[derive(Debug)]
+pub enum Event {
+    /// A complete header list was received on a stream.
+    Headers {
+        id: StreamIdentifier,
+        headers: Vec<HeaderField>,
+        end_stream: bool
+    },
+
+    /// Trailers, a header list ending the stream after its DATA, were
+    /// received on a stream.
+    Trailers {
+        id: StreamIdentifier,
+        headers: Vec<HeaderField>
+    },
+
+    /// DATA was received on a stream.
+    ///
+    /// The flow control space used by `data` must be returned with
+    /// `release` once it has been consumed.
+    Data {
+        id: StreamIdentifier,
+        data: Slice,
+        end_stream: bool
+    },
+
+    /// The peer reset a stream.
+    Reset {
+        id: StreamIdentifier,
+        error: ErrorCode
+    },
+
+    /// The peer sent GOAWAY. Streams we opened after `last` were not
+    /// processed, and have been closed.
+    GoAway {
+        last: StreamIdentifier,
+        error: ErrorCode
+    }
 }
 
 impl Http2 {
     pub fn new() -> Http2 { Http2::default() }
 
+    /// The state of a connection we opened as a client.
+    pub fn client() -> Http2 {
+        Http2 { role: Role::Client, ..Http2::default() }
+    }
+
+    /// Send our settings to the peer.
+    ///
+    /// The settings take effect once the peer acknowledges them.
+    pub fn send_settings(&mut self, settings: Settings) {
+        let frame = Frame::new(Flag::empty(), StreamIdentifier(0), settings.payload());
+
+        self.pending.push_back(settings);
+        self.outgoing.enqueue(frame, move |_: &mut Http2| {
+            debug!("Wrote SETTINGS frame {:?}", settings);
+        });
+    }
+
+    /// Are there any settings the peer has not yet acknowledged?
+    pub fn settings_pending(&self) -> bool { !self.pending.is_empty() }
+
     pub fn stream(&mut self, id: StreamIdentifier) -> Stream {
+        let send_window = self.remote.initial_window_size;
+        let recv_window = self.local.initial_window_size;
+
+        // Streams the peer could have opened, but did not before opening a
+        // later stream, are closed.
+        let skipped = self.is_peer_stream(id) && id.0 <= self.last_stream;
+
         self.streams.entry(id)
-            .or_insert_with(|| Some(Stream::new(id)))
+            .or_insert_with(|| {
+                let mut stream = Stream::new(id, send_window, recv_window);
+                if skipped { stream.close() }
+                Some(stream)
+            })
             .take().expect("Recursively applied frame to stream.")
     }
 
     pub fn apply(&mut self, frame: Frame) -> Result<()> {
         debug!("Applying frame {:?}", frame);
 
+        if !self.received_settings {
+            if frame.header.kind != Kind::Settings {
+                return Err(Error::connection(PROTOCOL_ERROR, "expected SETTINGS frame first"))
+            }
+
+            self.received_settings = true;
+        }
+
+        let frame = match try!(self.assemble(frame)) {
+            Some(frame) => frame,
+            None => return Ok(())
+        };
+
+        if frame.header.kind == Kind::Settings {
+            return self.settings(frame)
+        }
+
         let id = frame.header.id;
-        let stream = try!(self.stream(id).apply(self, frame));
-        self.streams.insert(id, Some(stream));
+        if id.0 == 0 {
+            return self.connection_frame(frame)
+        }
+
+        // PRIORITY_UPDATE is only sent on stream 0, see Section 7.1 of RFC 9218.
+        if let Payload::PriorityUpdate(_) = frame.payload {
+            return Err(Error::connection(PROTOCOL_ERROR, "PRIORITY_UPDATE on a stream"))
+        }
+
+        // PRIORITY does not open a stream, so for a stream which is idle, or
+        // closed and forgotten, only the priority is recorded.
+        if frame.header.kind == Kind::Priority && !self.streams.contains_key(&id) {
+            return match frame.payload.priority() {
+                Some(priority) => self.prioritize(id, Dependency::from(priority)),
+                None => Ok(())
+            }
+        }
+
+        // DATA counts against the connection window even if the stream
+        // later rejects it.
+        let data = match frame.payload {
+            Payload::Data(_) => {
+                try!(self.recv_window.consume(frame.header.length as usize));
+                Some(frame.header.length as usize)
+            },
+            _ => None
+        };
+
+        // Every complete header block must be decoded, even if the frame
+        // is later rejected, to keep the dynamic table in sync.
+        let headers = match frame.payload {
+            Payload::Headers { ref block, .. } | Payload::PushPromise { ref block, .. } =>
+                Some(try!(self.decoder.decode(block).map_err(|e| {
+                    debug!("Failed to decode header block: {:?}", e);
+                    Error::connection(COMPRESSION_ERROR, "invalid header block")
+                }))),
+            _ => None
+        };
+
+        // After sending GOAWAY no new streams are processed, see Section 6.8
+        // of the spec. Only the connection flow control window used by
+        // ignored DATA needs to be restored.
+        if let Some(last) = self.go_away {
+            if self.is_peer_stream(id) && id.0 > last {
+                debug!("Ignoring frame on stream {:?} opened after GOAWAY.", id);
+                return self.release(id, data.unwrap_or(0))
+            }
+        }
+
+        let kind = frame.header.kind;
+
+        // HEADERS on an idle stream opens it, see Section 5.1.1 of the spec.
+        let opening = kind == Kind::Headers && match self.streams.get(&id) {
+            Some(&Some(ref stream)) => stream.state() == State::Idle,
+            _ => true
+        };
+
+        let mut refused = false;
+        if opening {
+            // Servers only open streams by promising them, see Section 8.2.
+            if self.role == Role::Client {
+                return Err(Error::connection(PROTOCOL_ERROR, "server opened stream"))
+            }
+
+            if !self.is_peer_stream(id) {
+                return Err(Error::connection(PROTOCOL_ERROR, "client opened even stream"))
+            }
+
+            if id.0 <= self.last_stream {
+                return Err(Error::connection(PROTOCOL_ERROR, "stream id not increasing"))
+            }
+
+            self.forget_idle(id);
+            self.last_stream = id.0;
+
+            // Streams beyond our advertised limit are refused, so the client
+            // can safely retry them, see Section 5.1.2 of the spec.
+            if let Some(max) = self.local.max_concurrent_streams {
+                refused = self.active_peer_streams() >= max as usize;
+            }
+        }
+
+        let dependency = frame.payload.priority().map(Dependency::from);
+
+        // A stream cannot depend on itself, see Section 5.3.1 of the spec.
+        // Such a stream is reset before any request on it is delivered.
+        if let Some(ref dependency) = dependency {
+            if dependency.id == id {
+                return Err(Error::stream(id, PROTOCOL_ERROR, "stream depends on itself"))
+            }
+        }
+        let end_stream = frame.header.flag.contains(Flag::end_stream());
+        let mut event = match frame.payload {
+            Payload::Data(ref data) =>
+                Some(Event::Data { id: id, data: data.clone(), end_stream: end_stream }),
+            Payload::Reset(error) => Some(Event::Reset { id: id, error: error }),
+            _ => None
+        };
+
+        let mut stream = self.stream(id);
+
+        // Frames still in flight on a stream we reset are of no interest.
+        let closed = stream.state() == State::Closed;
+        let trailers = stream.received_headers();
+        let mut result = stream.apply(self, frame);
+
+        // Interim (1xx) responses precede the final response, see Section
+        // 8.1 of the spec.
+        let interim = match (kind, self.role, &headers) {
+            (Kind::Headers, Role::Client, &Some(ref headers)) if !trailers =>
+                validate::interim(headers),
+            _ => false
+        };
+
+        if let Some(headers) = headers {
+            debug!("Decoded headers {:?} on stream {:?}", headers, id);
+
+            // A PRIORITY_UPDATE received before the request takes precedence
+            // over the priority header, see Section 7 of RFC 9218.
+            // Only requests carry the priority of their response.
+            let priority = headers.iter().position(|field| &*field.name == b"priority");
+            if let (Role::Server, Some(index)) = (self.role, priority) {
+                if !self.outgoing.urgency.contains(id) {
+                    self.outgoing.urgency.set(id, Urgency::parse(&headers[index].value));
+                }
+
+                self.outgoing.extensible = true;
+            }
+
+            // Malformed requests and responses must be treated as stream
+            // errors of type PROTOCOL_ERROR, see Section 8.1.2.6 of the spec.
+            let valid = match (kind, self.role) {
+                (Kind::Headers, _) if trailers => validate::trailers(&headers),
+                (Kind::Headers, Role::Server) => validate::request(&headers),
+                (Kind::Headers, Role::Client) if interim && end_stream =>
+                    Err("interim response with END_STREAM"),
+                (Kind::Headers, Role::Client) => validate::response(&headers),
+                _ => Ok(())
+            };
+
+            event = match (kind, valid) {
+                (_, Err(message)) => {
+                    debug!("Malformed header list on stream {:?}: {}", id, message);
+                    result = result.and(Err(Error::stream(id, PROTOCOL_ERROR, message)));
+                    None
+                },
+                // There is no way to deliver interim responses, so only the
+                // final response is.
+                (Kind::Headers, Ok(())) if interim => {
+                    debug!("Ignoring interim response on stream {:?}", id);
+                    stream.interim();
+                    None
+                },
+                (Kind::Headers, Ok(())) if trailers => Some(Event::Trailers {
+                    id: id,
+                    headers: headers
+                }),
+                (Kind::Headers, Ok(())) if self.role == Role::Server => Some(Event::Headers {
+                    id: id,
+                    headers: validate::join_cookies(headers),
+                    end_stream: end_stream
+                }),
+                (Kind::Headers, Ok(())) => Some(Event::Headers {
+                    id: id,
+                    headers: headers,
+                    end_stream: end_stream
+                }),
+                (_, Ok(())) => event
+            };
+        }
+
+        self.replace(stream);
+        self.collect();
+
+        if refused {
+            debug!("Refusing stream {:?}, too many concurrent streams.", id);
+            result = result.and(Err(Error::stream(id, REFUSED_STREAM,
+                                                  "too many concurrent streams")));
+        }
+
+        let delivered = match (&result, event, closed) {
+            (&Ok(()), Some(event), false) => {
+                // The space used by DATA is released once the data has been
+                // consumed, but padding is released immediately.
+                let unpadded = match event {
+                    Event::Data { ref data, .. } => data.len(),
+                    _ => 0
+                };
+
+                self.events.push_back(event);
+                unpadded
+            },
+            _ => 0
+        };
+
+        if let Some(length) = data {
+            try!(self.release(id, length - delivered));
+        }
+
+        if let (&Ok(()), Some(dependency)) = (&result, dependency) {
+            try!(self.prioritize(id, dependency));
+        }
+
+        result
+    }
+
+    // Record the RFC 7540 priority of a stream, from HEADERS or PRIORITY.
+    fn prioritize(&mut self, id: StreamIdentifier, dependency: Dependency) -> Result<()> {
+        // A stream cannot depend on itself, see Section 5.3.1 of the spec.
+        if dependency.id == id {
+            return Err(Error::stream(id, PROTOCOL_ERROR, "stream depends on itself"))
+        }
+
+        // Peers which disable RFC 7540 priorities send no meaningful
+        // dependencies, see Section 2.1 of RFC 9218.
+        if !self.remote.no_rfc7540_priorities && self.track_priority(id) {
+            self.outgoing.tree.prioritize(id, dependency);
+        }
 
         Ok(())
     }
 
-}
+    /// Send a frame, updating the state of the stream it is sent on.
+    pub fn send(&mut self, frame: Frame) -> Result<()> {
+        let header = frame.header;
+        self.send_with(frame, move |_: &mut Http2| {
+            debug!("Wrote frame {:?}", header);
+        })
+    }
 
-impl Outgoing {
-    /// Queue a frame for writing.
+    /// Send a frame, calling `cb` once it has been written to the socket.
+    pub fn send_with<F>(&mut self, frame: Frame, cb: F) -> Result<()>
+    where F: for<'a> FnBox<(&'a mut Http2,), Output=()> + Send + 'static {
+        try!(self.transition(&frame));
+        self.outgoing.enqueue(frame, cb);
+        self.collect();
+        Ok(())
+    }
+
+    // Update the state of the stream a frame is sent on, and of the stream
+    // it promises.
+    fn transition(&mut self, frame: &Frame) -> Result<()> {
+        let id = frame.header.id;
+
+        if id.0 != 0 {
+            let mut stream = self.stream(id);
+            let result = stream.send(frame);
+            self.replace(stream);
+            try!(result);
+        }
+
+        // Sending PUSH_PROMISE reserves the promised stream.
+        if let Payload::PushPromise { promised, .. } = frame.payload {
+            let mut stream = self.stream(promised);
+            let result = stream.reserve(true);
+            self.replace(stream);
+            try!(result);
+        }
+
+        Ok(())
+    }
+
+    /// Send a header list on a stream.
+    ///
+    /// The header list is encoded once it is written, since the peer must
+    /// decode blocks in the order we encode them to keep its dynamic table
+    /// in sync with ours.
+    pub fn send_headers(&mut self, id: StreamIdentifier, fields: &[HeaderField],
+                        end_stream: bool) -> Result<()> {
+        let flag = if end_stream { Flag::end_stream() } else { Flag::empty() };
+        self.send_block(Block { id: id, flag: flag, promised: None, fields: fields.to_vec() })
+    }
+
+    /// Promise to push a response to the request described by `fields`,
+    /// sending PUSH_PROMISE on the stream of the request it is associated
+    /// with. Returns the reserved stream, on which the response is sent.
+    ///
+    /// See Section 8.2 of the spec.
+    pub fn push_promise(&mut self, parent: StreamIdentifier,
+                        fields: &[HeaderField]) -> Result<StreamIdentifier> {
+        if self.role == Role::Client {
+            return Err(Error::stream(parent, PROTOCOL_ERROR, "clients cannot push"))
+        }
+
+        if !self.remote.enable_push {
+            return Err(Error::stream(parent, REFUSED_STREAM, "peer disabled push"))
+        }
+
+        if self.received_go_away {
+            return Err(Error::stream(parent, REFUSED_STREAM, "peer sent GOAWAY"))
+        }
+
+        if self.at_stream_limit() {
+            return Err(Error::stream(parent, REFUSED_STREAM, "too many pushed streams"))
+        }
+
+        let promised = self.next_local_stream();
+        try!(self.send_block(Block {
+            id: parent,
+            flag: Flag::empty(),
+            promised: Some(promised),
+            fields: fields.to_vec()
+        }));
+
+        self.last_local = promised.0;
+        Ok(promised)
+    }
+
+    /// Send a request on a new stream, returning the stream it was sent on.
+    ///
+    /// Requests are only sent by clients, and not once the server has sent
+    /// GOAWAY or the server's stream limit has been reached.
+    pub fn send_request(&mut self, fields: &[HeaderField],
+                        end_stream: bool) -> Result<StreamIdentifier> {
+        let id = self.next_local_stream();
+
+        if self.role == Role::Server {
+            return Err(Error::stream(id, INTERNAL_ERROR, "servers cannot send requests"))
+        }
+
+        if self.going_away() {
+            return Err(Error::stream(id, REFUSED_STREAM, "connection is going away"))
+        }
+
+        if self.at_stream_limit() {
+            return Err(Error::stream(id, REFUSED_STREAM, "too many concurrent streams"))
+        }
+
+        try!(self.send_headers(id, fields, end_stream));
+
+        self.last_local = id.0;
+        Ok(id)
+    }
+
+    /// Could a request be sent on a new stream right now?
+    pub fn can_send_request(&self) -> bool {
+        self.role == Role::Client && !self.going_away() && !self.at_stream_limit()
+    }
+
+    // Has either side sent GOAWAY?
+    fn going_away(&self) -> bool {
+        self.received_go_away || self.go_away.is_some()
+    }
+
+    // Have we opened as many streams as the peer allows?
+    fn at_stream_limit(&self) -> bool {
+        self.remote.max_concurrent_streams.map_or(false, |max| {
+            self.active_local_streams() >= max as usize
+        })
+    }
+
+    // Queue a header block, changing the state of its stream as if it
+    // were sent now.
+    fn send_block(&mut self, block: Block) -> Result<()> {
+        let empty = util::slice(&[]);
+        let payload = match block.promised {
+            Some(promised) => Payload::PushPromise { promised: promised, block: empty },
+            None => Payload::Headers { priority: None, block: empty }
+        };
+
+        let id = block.id;
+        try!(self.transition(&Frame::new(block.flag, id, payload)));
+
+        self.outgoing.enqueue_block(block, move |_: &mut Http2| {
+            debug!("Wrote header block on {:?}", id);
+        });
+        self.collect();
+        Ok(())
+    }
+
+    /// Send DATA on a stream, split into frames no larger than the peer's
+    /// maximum frame size.
+    pub fn send_data(&mut self, id: StreamIdentifier, data: &[u8],
+                     end_stream: bool) -> Result<()> {
+        self.send_data_with(id, data, end_stream, move |_: &mut Http2| {
+            debug!("Wrote DATA on {:?}", id);
+        })
+    }
+
+    /// Send DATA on a stream, calling `cb` once the last frame has been
+    /// written to the socket.
     ///
-    /// The callback will be called when the frame has been fully written
-    /// to the socket.
-    pub fn enqueue<F>(&mut self, frame: Frame, cb: F)
+    /// DATA is only written when flow control allows, so `cb` can be used
+    /// to wait for capacity before sending more.
+    pub fn send_data_with<F>(&mut self, id: StreamIdentifier, data: &[u8],
+                             end_stream: bool, cb: F) -> Result<()>
     where F: for<'a> FnBox<(&'a mut Http2,), Output=()> + Send + 'static {
-        self.queue.push_front((frame, WriteCallback(Box::new(cb))))
+        let max = self.remote.max_frame_size as usize;
+        let mut chunks = data.chunks(max).peekable();
+
+        // An empty body still needs a frame to carry END_STREAM, and a
+        // frame to call back from.
+        if chunks.peek().is_none() {
+            let flag = if end_stream { Flag::end_stream() } else { Flag::empty() };
+            return self.send_with(Frame::new(flag, id, Payload::Data(util::slice(&[]))), cb)
+        }
+
+        while let Some(chunk) = chunks.next() {
+            let frame = Frame::new(Flag::empty(), id, Payload::Data(util::slice(chunk)));
+
+            if chunks.peek().is_none() {
+                let mut frame = frame;
+                if end_stream { frame.header.flag.insert(Flag::end_stream()) }
+                return self.send_with(frame, cb)
+            }
+
+            try!(self.send(frame));
+        }
+
+        Ok(())
+    }
+
+    /// Return flow control capacity used by received DATA to the peer,
+    /// once the data has been consumed.
+    ///
+    /// Capacity is returned once half of a window has been freed, so that
+    /// small frames are not each answered with WINDOW_UPDATEs.
+    pub fn release(&mut self, id: StreamIdentifier, length: usize) -> Result<()> {
+        if length == 0 { return Ok(()) }
+
+        self.unreleased += length;
+        if self.unreleased >= DEFAULT_WINDOW_SIZE as usize / 2 {
+            let increment = mem::replace(&mut self.unreleased, 0);
+            try!(self.recv_window.increase(increment as u32));
+            self.outgoing.enqueue(window_update(StreamIdentifier(0), increment),
+                                  move |_: &mut Http2| {
+                debug!("Wrote connection WINDOW_UPDATE of {:?}", increment);
+            });
+        }
+
+        let threshold = self.local.initial_window_size as usize / 2;
+        let increment = match self.streams.get_mut(&id) {
+            Some(&mut Some(ref mut stream)) => {
+                // No more DATA can be received once the peer closes its side.
+                match stream.state() {
+                    State::Open | State::HalfClosedLocal => {},
+                    _ => return Ok(())
+                }
+
+                stream.unreleased += length;
+                if stream.unreleased < threshold { return Ok(()) }
+
+                let increment = mem::replace(&mut stream.unreleased, 0);
+                try!(stream.recv_window.increase(increment as u32));
+                increment
+            },
+            _ => return Ok(())
+        };
+
+        self.outgoing.enqueue(window_update(id, increment), move |_: &mut Http2| {
+            debug!("Wrote WINDOW_UPDATE of {:?} for {:?}", increment, id);
+        });
+
+        Ok(())
     }
 
-    /// Dequeue an encoder for writing to a socket.
+    /// Dequeue the next frame which can be written, respecting flow control.
     ///
-    /// Note: ensure that the callback is called when the encoder is finished.
+    /// Sending DATA consumes space in the connection and stream windows.
+    /// DATA larger than the space in both is split, and the rest stays
+    /// queued until the peer opens the windows further.
     pub fn dequeue(&mut self) -> Option<(FrameEncoder, WriteCallback)> {
-        self.queue.pop_back().map(|(frame, cb)| (FrameEncoder::from(frame), cb))
+        let next = {
+            let connection = self.send_window.available();
+            let max = self.remote.max_frame_size as usize;
+            let streams = &self.streams;
+            let encoder = &mut self.encoder;
+
+            self.outgoing.dequeue_where(|frame| {
+                match frame.payload {
+                    Payload::Data(_) => {
+                        let stream = match streams.get(&frame.header.id) {
+                            Some(&Some(ref stream)) => stream.send_window.available(),
+                            _ => 0
+                        };
+
+                        // Empty DATA, such as a frame only ending the stream,
+                        // needs no space in either window.
+                        let limit = cmp::min(max, cmp::min(connection, stream));
+                        if limit > 0 || frame.header.length == 0 { Some(limit) } else { None }
+                    },
+                    // Header blocks were split into frames of the maximum size
+                    // when they were encoded, which the peer may since have
+                    // lowered.
+                    Payload::Headers { .. } | Payload::PushPromise { .. } |
+                    Payload::Continuation(_) => Some(max),
+                    _ => Some(usize::MAX)
+                }
+            }, |block| encode_block(encoder, max, block))
+        };
+
+        next.map(|(frame, cb)| {
+            let id = frame.header.id;
+            let length = frame.header.length as usize;
+
+            // Padding must fit in the frame, and in flow control windows
+            // for DATA, where it counts towards flow control.
+            let mut limit = (self.remote.max_frame_size as usize).saturating_sub(length);
+            let data = match frame.payload {
+                Payload::Data(_) => {
+                    limit = cmp::min(limit, self.send_window.available() - length);
+                    if let Some(&Some(ref stream)) = self.streams.get(&id) {
+                        limit = cmp::min(limit, stream.send_window.available() - length);
+                    }
+
+                    true
+                },
+                Payload::Headers { .. } | Payload::PushPromise { .. } => false,
+                _ => {
+                    limit = 0;
+                    false
+                }
+            };
+
+            let (encoder, length) = match self.padding.padding(length, limit) {
+                Some(padding) =>
+                    (FrameEncoder::padded(frame, padding), length + 1 + padding as usize),
+                None => (FrameEncoder::from(frame), length)
+            };
+
+            // DATA was limited to the windows and its padding to what is left
+            // of them, so both must have room for the padded frame.
+            if data {
+                self.send_window.consume(length)
+                    .expect("Padded DATA exceeds the connection window.");
+
+                if let Some(&mut Some(ref mut stream)) = self.streams.get_mut(&id) {
+                    stream.send_window.consume(length)
+                        .expect("Padded DATA exceeds the stream window.");
+                }
+            }
+
+            (encoder, cb)
+        })
+    }
+
+    // Put back a stream taken with `stream`, remembering it if it closed.
+    fn replace(&mut self, stream: Stream) {
+        let id = stream.id();
+
+        let remembered = self.closed.iter().any(|&closed| closed == id);
+        if stream.state() == State::Closed && !remembered {
+            self.closed.push_back(id);
+        }
+
+        self.streams.insert(id, Some(stream));
+    }
+
+    // Forget the oldest closed streams, beyond the most recent
+    // CLOSED_STREAMS. Streams with frames still waiting to be written are
+    // kept until the frames are written.
+    fn collect(&mut self) {
+        let mut remaining = self.closed.len();
+
+        while self.closed.len() > CLOSED_STREAMS && remaining > 0 {
+            remaining -= 1;
+
+            let id = self.closed.pop_front().unwrap();
+            if self.outgoing.is_queued(id) {
+                self.closed.push_back(id);
+                continue
+            }
+
+            debug!("Forgetting closed stream {:?}", id);
+            self.streams.remove(&id);
+            self.outgoing.remove(id);
+        }
+    }
+
+    // Forget the priorities of idle streams the peer skipped by opening `id`,
+    // which is no longer idle. The skipped streams are closed, see Section
+    // 5.1.1 of the spec, without ever being tracked.
+    fn forget_idle(&mut self, id: StreamIdentifier) {
+        let skipped = self.idle.iter().cloned()
+            .take_while(|&skipped| skipped < id.0)
+            .collect::<Vec<_>>();
+
+        for skipped in skipped {
+            self.idle.remove(&skipped);
+            self.outgoing.remove(StreamIdentifier(skipped));
+        }
+
+        self.idle.remove(&id.0);
+    }
+
+    // Should a priority signalled for the stream be recorded?
+    //
+    // Priorities of closed streams are of no use. Only IDLE_STREAMS streams
+    // the peer has not yet opened are remembered, so that the peer cannot
+    // grow our state without limit.
+    fn track_priority(&mut self, id: StreamIdentifier) -> bool {
+        let known = match self.streams.get(&id) {
+            Some(&Some(ref stream)) => Some(stream.state() != State::Closed),
+            Some(&None) => Some(true),
+            None => None
+        };
+
+        match known {
+            Some(tracked) => tracked,
+            None if self.is_peer_stream(id) && id.0 > self.last_stream => {
+                if self.idle.len() < IDLE_STREAMS {
+                    self.idle.insert(id.0);
+                }
+
+                self.idle.contains(&id.0)
+            },
+            None => false
+        }
     }
 
-    /// Are there any frames remaining to be encoded?
-    pub fn is_empty(&self) -> bool {
-        self.queue.is_empty() && self.current.is_none()
+    // Could the peer have opened the stream?
+    fn is_peer_stream(&self, id: StreamIdentifier) -> bool {
+        match self.role {
+            Role::Server => is_client_stream(id),
+            Role::Client => !is_client_stream(id)
+        }
     }
+
+    // The id of the next stream we open or push, see Section 5.1.1 of the
+    // spec.
+    fn next_local_stream(&self) -> StreamIdentifier {
+        match (self.role, self.last_local) {
+            (Role::Client, 0) => StreamIdentifier(1),
+            (_, last) => StreamIdentifier(last + 2)
+        }
+    }
+
+    /// The number of streams being tracked, including recently closed
+    /// streams.
+    pub fn tracked_streams(&self) -> usize {
+        self.streams.len()
+    }
+
+    // The number of streams we have opened or pushed which are not yet
+    // closed.
+    fn active_local_streams(&self) -> usize {
+        self.streams.iter().filter(|&(id, stream)| {
+            !self.is_peer_stream(*id) && stream.as_ref().map_or(false, |stream| {
+                stream.state() != State::Idle && stream.state() != State::Closed
+            })
+        }).count()
+    }
+
+    // The number of streams the peer has opened which are open or
+    // half-closed. Reserved streams do not count towards our limit, see
+    // Section 5.1.2 of the spec.
+    fn active_peer_streams(&self) -> usize {
+        self.streams.iter().filter(|&(id, stream)| {
+            self.is_peer_stream(*id) && stream.as_ref().map_or(false, |stream| {
+                match stream.state() {
+                    State::Open | State::HalfClosedLocal | State::HalfClosedRemote => true,
+                    _ => false
+                }
+            })
+        }).count()
+    }
+
+    /// The number of streams which are open, half-closed or reserved.
+    pub fn active_streams(&self) -> usize {
+        self.streams.values().filter_map(Option::as_ref).filter(|stream| {
+            match stream.state() {
+                State::Idle | State::Closed => false,
+                _ => true
+            }
+        }).count()
+    }
+
+    /// Has either side sent GOAWAY, and all remaining streams finished?
+    ///
+    /// Once drained, the connection can be closed as soon as all outgoing
+    /// frames have been written.
+    pub fn drained(&self) -> bool {
+        (self.go_away.is_some() || self.received_go_away) && self.active_streams() == 0
+    }
+
+    /// Send a PING to the peer.
+    ///
+    /// The callback is called with the round trip time once the peer
+    /// acknowledges the PING. Returns the opaque data of the PING.
+    pub fn ping<F>(&mut self, cb: F) -> u64
+    where F: FnOnce(Duration) + Send + 'static {
+        let data = self.next_ping;
+        self.next_ping = self.next_ping.wrapping_add(1);
+
+        self.pings.insert(data, (Instant::now(), PingCallback(Box::new(cb))));
+
+        let frame = Frame::new(Flag::empty(), StreamIdentifier(0), Payload::Ping(data));
+        self.outgoing.enqueue(frame, move |http2: &mut Http2| {
+            debug!("Wrote PING {:?}", data);
+
+            // Measure from when the PING was written, not when it was queued.
+            if let Some(ping) = http2.pings.get_mut(&data) {
+                ping.0 = Instant::now();
+            }
+        });
+
+        data
+    }
+
+    /// Has the PING with the given opaque data not yet been acknowledged?
+    pub fn ping_pending(&self, data: u64) -> bool {
+        self.pings.contains_key(&data)
+    }
+
+    /// Set the priority of the response on a stream.
+    ///
+    /// This switches the connection to scheduling streams by urgency, as
+    /// defined in RFC 9218.
+    pub fn set_priority(&mut self, id: StreamIdentifier, urgency: Urgency) {
+        self.outgoing.urgency.set(id, urgency);
+        self.outgoing.extensible = true;
+    }
+
+    /// Reset a stream with the given error.
+    pub fn reset(&mut self, id: StreamIdentifier, error: ErrorCode) -> Result<()> {
+        self.send(Frame::new(Flag::empty(), id, Payload::Reset(error)))
+    }
+
+    fn connection_frame(&mut self, frame: Frame) -> Result<()> {
+        match frame.payload {
+            Payload::Ping(data) => {
+                if frame.header.flag.contains(Flag::ack()) {
+                    match self.pings.remove(&data) {
+                        Some((sent, cb)) => {
+                            let rtt = sent.elapsed();
+                            debug!("PING {:?} acknowledged after {:?}", data, rtt);
+                            cb.0.call_box((rtt,));
+                        },
+                        None => debug!("Ignoring unexpected PING ACK {:?}", data)
+                    }
+
+                    return Ok(())
+                }
+
+                let ack = Frame::new(Flag::ack(), StreamIdentifier(0), Payload::Ping(data));
+                self.outgoing.enqueue(ack, move |_: &mut Http2| {
+                    debug!("Wrote PING ACK {:?}", data);
+                });
+
+                Ok(())
+            },
+            Payload::GoAway { last, error, .. } => {
+                debug!("Received GOAWAY, last stream {:?}, error {:?}", last, error);
+                self.received_go_away = true;
+
+                // Streams after the last stream were not processed, and can be
+                // retried on another connection, see Section 6.8 of the spec.
+                let unprocessed = self.streams.iter()
+                    .filter_map(|(&id, stream)| stream.as_ref().map(|stream| (id, stream)))
+                    .filter(|&(id, stream)| {
+                        id.0 > last.0 && !self.is_peer_stream(id) &&
+                            stream.state() != State::Idle && stream.state() != State::Closed
+                    })
+                    .map(|(id, _)| id)
+                    .collect::<Vec<_>>();
+
+                for id in unprocessed {
+                    let mut stream = self.stream(id);
+                    stream.close();
+                    self.replace(stream);
+                }
+
+                self.collect();
+                self.events.push_back(Event::GoAway { last: last, error: error });
+                Ok(())
+            },
+            Payload::WindowUpdate(increment) => {
+                debug!("Received connection WINDOW_UPDATE {:?}", increment);
+
+                if increment.0 == 0 {
+                    return Err(Error::connection(PROTOCOL_ERROR,
+                                                 "WINDOW_UPDATE with zero increment"))
+                }
+
+                self.send_window.increase(increment.0)
+            },
+            Payload::PriorityUpdate(data) => {
+                // Only clients send PRIORITY_UPDATE, see Section 7.1 of RFC 9218.
+                if self.role == Role::Client {
+                    return Err(Error::connection(PROTOCOL_ERROR, "PRIORITY_UPDATE from server"))
+                }
+
+                if data.len() < 4 {
+                    return Err(Error::connection(FRAME_SIZE_ERROR, "PRIORITY_UPDATE too short"))
+                }
+
+                let id = StreamIdentifier(BigEndian::read_u32(&data[..4]) & 0x7fffffff);
+                if id.0 == 0 {
+                    return Err(Error::connection(PROTOCOL_ERROR, "PRIORITY_UPDATE for stream 0"))
+                }
+
+                let urgency = Urgency::parse(&data[4..]);
+                debug!("Received PRIORITY_UPDATE {:?} for {:?}", urgency, id);
+
+                if !self.track_priority(id) {
+                    debug!("Ignoring PRIORITY_UPDATE for {:?}", id);
+                    return Ok(())
+                }
+
+                self.outgoing.urgency.set(id, urgency);
+                self.outgoing.extensible = true;
+                Ok(())
+            },
+            Payload::Unregistered(_) => Ok(()),
+            payload => {
+                debug!("Received illegal frame {:?} on stream 0", payload);
+                Err(Error::connection(PROTOCOL_ERROR, "frame illegal on stream 0"))
+            }
+        }
+    }
+
+    /// Join HEADERS or PUSH_PROMISE frames with any following CONTINUATION
+    /// frames, returning the complete frame once END_HEADERS is received.
+    fn assemble(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        if let Some(mut block) = self.continuation.take() {
+            if frame.header.kind != Kind::Continuation {
+                return Err(Error::connection(PROTOCOL_ERROR, "expected CONTINUATION frame"))
+            }
+
+            return if try!(block.push(frame)) {
+                Ok(Some(block.finish()))
+            } else {
+                self.continuation = Some(block);
+                Ok(None)
+            }
+        }
+
+        match frame.header.kind {
+            Kind::Continuation =>
+                Err(Error::connection(PROTOCOL_ERROR, "unexpected CONTINUATION frame")),
+            Kind::Headers | Kind::PushPromise
+                if !frame.header.flag.contains(Flag::end_headers()) => {
+                debug!("Buffering header block on stream {:?}", frame.header.id);
+                self.continuation = Some(HeaderBlock::new(frame));
+                Ok(None)
+            },
+            _ => Ok(Some(frame))
+        }
+    }
+
+    fn settings(&mut self, frame: Frame) -> Result<()> {
+        if frame.header.id.0 != 0 {
+            return Err(Error::connection(PROTOCOL_ERROR, "SETTINGS on a stream"))
+        }
+
+        let settings = match frame.payload {
+            Payload::Settings(settings) => settings,
+            _ => return Err(Error::connection(PROTOCOL_ERROR, "expected SETTINGS payload"))
+        };
+
+        if frame.header.flag.contains(Flag::ack()) {
+            if settings.bytes().len() != 0 {
+                return Err(Error::connection(FRAME_SIZE_ERROR, "SETTINGS ACK with payload"))
+            }
+
+            match self.pending.pop_front() {
+                Some(local) => {
+                    debug!("Peer acknowledged settings {:?}", local);
+                    self.decoder.set_max_size(local.header_table_size as usize);
+
+                    let delta = local.initial_window_size as i64 -
+                        self.local.initial_window_size as i64;
+                    for stream in self.streams.values_mut().filter_map(Option::as_mut) {
+                        try!(stream.recv_window.adjust(delta));
+                    }
+
+                    self.local = local;
+                },
+                None => debug!("Ignoring unexpected SETTINGS ACK.")
+            }
+
+            return Ok(())
+        }
+
+        let initial_window_size = self.remote.initial_window_size;
+        try!(self.remote.apply(&settings));
+        debug!("Applied peer settings, now {:?}", self.remote);
+
+        // Changing the initial window size affects the send window of
+        // every stream, see Section 6.9.2 of the spec.
+        let delta = self.remote.initial_window_size as i64 - initial_window_size as i64;
+        for stream in self.streams.values_mut().filter_map(Option::as_mut) {
+            try!(stream.send_window.adjust(delta));
+        }
+
+        self.encoder.set_max_size(self.remote.header_table_size as usize);
+
+        if self.remote.no_rfc7540_priorities {
+            self.outgoing.extensible = true;
+        }
+
+        let ack = Frame::new(Flag::ack(), StreamIdentifier(0), Settings::ack());
+        self.outgoing.enqueue(ack, move |_: &mut Http2| {
+            debug!("Wrote SETTINGS ACK.");
+        });
+
+        Ok(())
+    }
+
+    /// Queue a GOAWAY frame with the given error code.
+    ///
+    /// The last stream identifier is the highest stream id we have processed,
+    /// frames on any later streams are ignored from now on.
+    pub fn go_away(&mut self, error: ErrorCode) {
+        self.go_away = Some(self.last_stream);
+
+        let frame = Frame::new(Flag::empty(), StreamIdentifier(0), Payload::GoAway {
+            last: StreamIdentifier(self.last_stream),
+            error: error,
+            data: util::slice(&[])
+        });
+
+        self.outgoing.enqueue(frame, move |_: &mut Http2| {
+            debug!("Wrote GOAWAY frame with error {:?}", error);
+        });
+    }
+}
+
+// Streams opened by clients have odd ids, see Section 5.1.1 of the spec.
+fn is_client_stream(id: StreamIdentifier) -> bool {
+    id.0 % 2 == 1
 }
 
-pub struct WriteCallback(pub Box<for<'a> FnBox<(&'a mut Http2,), Output=()> + Send>);
+// Encode a header block, continued in CONTINUATION frames if it is larger
+// than the peer's maximum frame size, see Section 6.10 of the spec.
+fn encode_block(encoder: &mut hpack::Encoder, max: usize, block: Block) -> Vec<Frame> {
+    let encoded = encoder.encode(&block.fields);
+
+    // The promised stream takes 4 bytes of the first frame.
+    let overhead = if block.promised.is_some() { 4 } else { 0 };
+    let (first, rest) = encoded.split_at(cmp::min(encoded.len(), max - overhead));
+
+    let mut flag = block.flag;
+    if rest.is_empty() { flag.insert(Flag::end_headers()) }
+
+    let payload = match block.promised {
+        Some(promised) => Payload::PushPromise { promised: promised, block: util::slice(first) },
+        None => Payload::Headers { priority: None, block: util::slice(first) }
+    };
+
+    let mut frames = vec![Frame::new(flag, block.id, payload)];
+    let mut rest = rest.chunks(max).peekable();
+
+    while let Some(chunk) = rest.next() {
+        let flag = if rest.peek().is_none() { Flag::end_headers() } else { Flag::empty() };
+        frames.push(Frame::new(flag, block.id, Payload::Continuation(util::slice(chunk))));
+    }
+
+    frames
+}
 
-impl fmt::Debug for WriteCallback {
+fn window_update(id: StreamIdentifier, length: usize) -> Frame {
+    Frame::new(Flag::empty(), id, Payload::WindowUpdate(SizeIncrement(length as u32)))
+}
+
+struct PingCallback(Box<FnBox(Duration) + Send>);
+
+impl fmt::Debug for PingCallback {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str("Write Callback")
+        f.write_str("Ping Callback")
     }
 }
 
+#[cfg(test)]
+mod test {
+    use http::parser::{Frame, FrameHeader, Payload, Flag, Kind, StreamIdentifier,
+                       SizeIncrement};
+    use http::encoder::{Encoder, EncodeResult};
+    use http::hpack::{self, HeaderField};
+    use http::settings::{Settings, MIN_FRAME_SIZE};
+    use http::error::{PROTOCOL_ERROR, REFUSED_STREAM, CANCEL};
+    use util;
+
+    use byteorder::{ByteOrder, BigEndian};
+
+    use super::{Http2, Event, IDLE_STREAMS, window_update};
+
+    // A server which has received the client's settings.
+    fn server(settings: Settings) -> Http2 {
+        let mut http2 = Http2::new();
+        http2.apply(Frame::new(Flag::empty(), StreamIdentifier(0), settings.payload())).unwrap();
+        http2
+    }
+
+    fn request(encoder: &mut hpack::Encoder, id: u32, flag: Flag) -> Frame {
+        let block = encoder.encode(&[
+            HeaderField::new(":method", "GET"),
+            HeaderField::new(":scheme", "https"),
+            HeaderField::new(":path", "/")
+        ]);
+
+        Frame::new(flag | Flag::end_headers(), StreamIdentifier(id), Payload::Headers {
+            priority: None,
+            block: util::slice(&block)
+        })
+    }
+
+    fn response(encoder: &mut hpack::Encoder, id: u32, status: &str, flag: Flag) -> Frame {
+        let block = encoder.encode(&[HeaderField::new(":status", status)]);
+
+        Frame::new(flag | Flag::end_headers(), StreamIdentifier(id), Payload::Headers {
+            priority: None,
+            block: util::slice(&block)
+        })
+    }
+
+    // Write all frames which can be written now, and parse them as the
+    // peer would.
+    fn written(http2: &mut Http2) -> Vec<Frame> {
+        let mut frames = Vec::new();
+
+        while let Some((mut encoder, cb)) = http2.dequeue() {
+            let mut buf = Vec::new();
+            loop {
+                match encoder.encode(&mut buf) {
+                    EncodeResult::Wrote(_) => continue,
+                    EncodeResult::Finished => break,
+                    e => panic!("Bad encode result {:?}", e)
+                }
+            }
+
+            cb.0.call_box((&mut *http2,));
+            frames.push(parse(&buf));
+        }
+
+        frames
+    }
+
+    fn parse(buf: &[u8]) -> Frame {
+        let header = FrameHeader::parse(buf).unwrap();
+        Frame::parse(header, util::slice(&buf[9..])).unwrap()
+    }
+
+    fn priority(id: u32, dependency: u32) -> Frame {
+        let mut buf = vec![0u8, 0, 5, 0x2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 15];
+        BigEndian::write_u32(&mut buf[5..9], id);
+        BigEndian::write_u32(&mut buf[9..13], dependency);
+        parse(&buf)
+    }
+
+    fn priority_update(stream: u32, id: u32, value: &str) -> Frame {
+        let mut buf = vec![0u8; 4];
+        BigEndian::write_u32(&mut buf, id);
+        buf.extend_from_slice(value.as_bytes());
+
+        Frame::new(Flag::empty(), StreamIdentifier(stream),
+                   Payload::PriorityUpdate(util::slice(&buf)))
+    }
+
+    // The DATA among frames, with whether it ended the stream.
+    fn data(frames: &[Frame]) -> Vec<(Vec<u8>, bool)> {
+        frames.iter().filter_map(|frame| match frame.payload {
+            Payload::Data(ref data) =>
+                Some((data.to_vec(), frame.header.flag.contains(Flag::end_stream()))),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn test_data_split_to_window() {
+        let mut http2 = server(Settings { initial_window_size: 1, ..Settings::default() });
+        let mut encoder = hpack::Encoder::new();
+        let id = StreamIdentifier(1);
+
+        http2.apply(request(&mut encoder, 1, Flag::end_stream())).unwrap();
+        http2.send_headers(id, &[HeaderField::new(":status", "200")], false).unwrap();
+        http2.send_data(id, b"hello", true).unwrap();
+        assert_eq!(data(&written(&mut http2)), vec![(b"h".to_vec(), false)]);
+
+        http2.apply(window_update(id, 2)).unwrap();
+        assert_eq!(data(&written(&mut http2)), vec![(b"el".to_vec(), false)]);
+
+        http2.apply(window_update(id, 10)).unwrap();
+        assert_eq!(data(&written(&mut http2)), vec![(b"lo".to_vec(), true)]);
+    }
+
+    #[test]
+    fn test_window_updates_batched() {
+        let mut http2 = server(Settings::default());
+        let mut encoder = hpack::Encoder::new();
+        let id = StreamIdentifier(1);
+
+        http2.apply(request(&mut encoder, 1, Flag::empty())).unwrap();
+        written(&mut http2);
+
+        let receive = |http2: &mut Http2| {
+            http2.apply(Frame::new(Flag::empty(), id, Payload::Data(util::slice(&[0; 1000]))))
+                .unwrap();
+            http2.release(id, 1000).unwrap();
+
+            written(http2).into_iter().filter_map(|frame| match frame.payload {
+                Payload::WindowUpdate(SizeIncrement(increment)) =>
+                    Some((frame.header.id, increment)),
+                _ => None
+            }).collect::<Vec<_>>()
+        };
+
+        // Nothing is returned until half of the windows has been consumed.
+        for _ in 0..32 { assert!(receive(&mut http2).is_empty()) }
+        assert_eq!(receive(&mut http2),
+                   vec![(StreamIdentifier(0), 33000), (id, 33000)]);
+        assert!(receive(&mut http2).is_empty());
+    }
+
+    #[test]
+    fn test_self_dependency() {
+        let mut http2 = server(Settings::default());
+        let block = hpack::Encoder::new().encode(&[
+            HeaderField::new(":method", "GET"),
+            HeaderField::new(":scheme", "https"),
+            HeaderField::new(":path", "/")
+        ]);
+
+        // HEADERS with END_HEADERS and PRIORITY on stream 1, depending on
+        // stream 1 with weight 16.
+        let mut buf = vec![0, 0, 5 + block.len() as u8, 0x1, 0x24, 0, 0, 0, 1,
+                           0, 0, 0, 1, 15];
+        buf.extend_from_slice(&block);
+
+        let error = http2.apply(parse(&buf)).unwrap_err();
+        assert_eq!(error.code(), PROTOCOL_ERROR);
+        assert_eq!(error.stream_id(), Some(StreamIdentifier(1)));
+        assert!(http2.events.is_empty());
+    }
+
+    #[test]
+    fn test_priority_update() {
+        let mut http2 = server(Settings::default());
+        let error = http2.apply(priority_update(1, 1, "u=0")).unwrap_err();
+        assert_eq!(error.code(), PROTOCOL_ERROR);
+        assert_eq!(error.stream_id(), None);
+
+        // Only so many idle streams are prioritized.
+        let mut http2 = server(Settings::default());
+        for n in 0..IDLE_STREAMS as u32 + 10 {
+            http2.apply(priority_update(0, 2 * n + 3, "u=0")).unwrap();
+        }
+
+        let last = StreamIdentifier(2 * IDLE_STREAMS as u32 + 3);
+        assert!(http2.outgoing.urgency.contains(StreamIdentifier(3)));
+        assert!(!http2.outgoing.urgency.contains(last));
+
+        // Opening stream 5 skips stream 3, whose priority is forgotten.
+        let mut encoder = hpack::Encoder::new();
+        http2.apply(request(&mut encoder, 5, Flag::end_stream())).unwrap();
+        assert!(!http2.outgoing.urgency.contains(StreamIdentifier(3)));
+        assert!(http2.outgoing.urgency.contains(StreamIdentifier(5)));
+
+        // Stream 3 is closed, so is not prioritized again, leaving room for
+        // another idle stream.
+        http2.apply(priority_update(0, 3, "u=0")).unwrap();
+        assert!(!http2.outgoing.urgency.contains(StreamIdentifier(3)));
+
+        http2.apply(priority_update(0, last.0, "u=0")).unwrap();
+        assert!(http2.outgoing.urgency.contains(last));
+    }
+
+    #[test]
+    fn test_headers_on_reset_stream() {
+        let mut http2 = server(Settings::default());
+        let mut encoder = hpack::Encoder::new();
+        http2.apply(request(&mut encoder, 1, Flag::end_stream())).unwrap();
+        http2.apply(request(&mut encoder, 3, Flag::end_stream())).unwrap();
+        http2.apply(Frame::new(Flag::empty(), StreamIdentifier(1), Payload::Reset(CANCEL)))
+            .unwrap();
+
+        let cancelled = [HeaderField::new(":status", "200"), HeaderField::new("x-id", "1")];
+        assert!(http2.send_headers(StreamIdentifier(1), &cancelled, true).is_err());
+
+        let response = [HeaderField::new(":status", "200"), HeaderField::new("x-id", "3")];
+        http2.send_headers(StreamIdentifier(3), &response, true).unwrap();
+
+        let frames = written(&mut http2);
+        let block = frames.iter().filter_map(|frame| match frame.payload {
+            Payload::Headers { ref block, .. } => Some(block.to_vec()),
+            _ => None
+        }).collect::<Vec<_>>();
+
+        assert_eq!(block.len(), 1);
+        assert_eq!(hpack::Decoder::new().decode(&block[0]).unwrap(), response.to_vec());
+    }
+
+    // The header lists of the header blocks in `frames`, decoded in order.
+    fn decoded(decoder: &mut hpack::Decoder, frames: &[Frame]) -> Vec<Vec<HeaderField>> {
+        frames.iter().filter_map(|frame| match frame.payload {
+            Payload::Headers { ref block, .. } | Payload::PushPromise { ref block, .. } =>
+                Some(decoder.decode(block).unwrap()),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn test_header_blocks_behind_data() {
+        let mut http2 = server(Settings { initial_window_size: 1, ..Settings::default() });
+        let mut encoder = hpack::Encoder::new();
+        http2.apply(request(&mut encoder, 1, Flag::end_stream())).unwrap();
+        http2.apply(request(&mut encoder, 3, Flag::end_stream())).unwrap();
+
+        let one = [HeaderField::new(":status", "200"), HeaderField::new("x-id", "1")];
+        let trailers = [HeaderField::new("x-trailer", "1")];
+        http2.send_headers(StreamIdentifier(1), &one, false).unwrap();
+        http2.send_data(StreamIdentifier(1), b"hello", false).unwrap();
+        http2.send_headers(StreamIdentifier(1), &trailers, true).unwrap();
+
+        let three = [HeaderField::new(":status", "200"), HeaderField::new("x-id", "3")];
+        http2.send_headers(StreamIdentifier(3), &three, true).unwrap();
+
+        // The trailers wait for the window, without holding up stream 3.
+        let mut decoder = hpack::Decoder::new();
+        let blocks = decoded(&mut decoder, &written(&mut http2));
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks.contains(&one.to_vec()) && blocks.contains(&three.to_vec()));
+
+        http2.apply(window_update(StreamIdentifier(1), 10)).unwrap();
+        let frames = written(&mut http2);
+        assert_eq!(data(&frames), vec![(b"ello".to_vec(), false)]);
+        assert_eq!(decoded(&mut decoder, &frames), vec![trailers.to_vec()]);
+    }
+
+    #[test]
+    fn test_push_waits_for_promise() {
+        let mut http2 = server(Settings { initial_window_size: 1, ..Settings::default() });
+        let mut encoder = hpack::Encoder::new();
+        let parent = StreamIdentifier(1);
+        http2.apply(request(&mut encoder, 1, Flag::end_stream())).unwrap();
+
+        http2.send_headers(parent, &[HeaderField::new(":status", "200")], false).unwrap();
+        http2.send_data(parent, b"hello", false).unwrap();
+
+        let pushed = [HeaderField::new(":method", "GET"), HeaderField::new(":scheme", "https"),
+                      HeaderField::new(":path", "/style.css")];
+        let promised = http2.push_promise(parent, &pushed).unwrap();
+        http2.send_headers(promised, &[HeaderField::new(":status", "200")], true).unwrap();
+
+        // Nothing is sent on the promised stream before its PUSH_PROMISE.
+        assert!(written(&mut http2).iter().all(|frame| frame.header.id != promised));
+
+        http2.apply(window_update(parent, 10)).unwrap();
+        let kinds = written(&mut http2).iter()
+            .filter(|frame| frame.header.kind != Kind::Data)
+            .map(|frame| (frame.header.id, frame.header.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec![(parent, Kind::PushPromise), (promised, Kind::Headers)]);
+    }
+
+    #[test]
+    fn test_pushed_streams_not_counted_towards_limit() {
+        let mut http2 = server(Settings::default());
+        http2.send_settings(Settings { max_concurrent_streams: Some(2), ..Settings::default() });
+        http2.apply(Frame::new(Flag::ack(), StreamIdentifier(0), Settings::ack())).unwrap();
+
+        let mut encoder = hpack::Encoder::new();
+        http2.apply(request(&mut encoder, 1, Flag::empty())).unwrap();
+
+        let pushed = [HeaderField::new(":method", "GET"), HeaderField::new(":scheme", "https"),
+                      HeaderField::new(":path", "/style.css")];
+        let first = http2.push_promise(StreamIdentifier(1), &pushed).unwrap();
+        http2.push_promise(StreamIdentifier(1), &pushed).unwrap();
+        http2.send_headers(first, &[HeaderField::new(":status", "200")], false).unwrap();
+
+        // Only the streams the client opened count towards our limit.
+        http2.apply(request(&mut encoder, 3, Flag::empty())).unwrap();
+
+        let error = http2.apply(request(&mut encoder, 5, Flag::empty())).unwrap_err();
+        assert_eq!(error.code(), REFUSED_STREAM);
+        assert_eq!(error.stream_id(), Some(StreamIdentifier(5)));
+    }
+
+    #[test]
+    fn test_interim_responses() {
+        let mut http2 = Http2::client();
+        http2.apply(Frame::new(Flag::empty(), StreamIdentifier(0),
+                               Settings::default().payload())).unwrap();
+
+        let get = [HeaderField::new(":method", "GET"), HeaderField::new(":scheme", "https"),
+                   HeaderField::new(":path", "/")];
+        let id = http2.send_request(&get, true).unwrap();
+
+        // Only the final response is delivered, and may be followed by DATA.
+        let mut encoder = hpack::Encoder::new();
+        http2.apply(response(&mut encoder, id.0, "100", Flag::empty())).unwrap();
+        http2.apply(response(&mut encoder, id.0, "103", Flag::empty())).unwrap();
+        assert!(http2.events.is_empty());
+
+        http2.apply(response(&mut encoder, id.0, "200", Flag::empty())).unwrap();
+        http2.apply(Frame::new(Flag::end_stream(), id, Payload::Data(util::slice(b"ok"))))
+            .unwrap();
+
+        match http2.events.pop_front() {
+            Some(Event::Headers { headers, end_stream: false, .. }) =>
+                assert_eq!(headers, vec![HeaderField::new(":status", "200")]),
+            event => panic!("Expected the final response, got {:?}", event)
+        }
+
+        match http2.events.pop_front() {
+            Some(Event::Data { end_stream: true, .. }) => {},
+            event => panic!("Expected DATA, got {:?}", event)
+        }
+
+        // An interim response cannot end the stream.
+        let id = http2.send_request(&get, true).unwrap();
+        let error = http2.apply(response(&mut encoder, id.0, "103", Flag::end_stream()))
+            .unwrap_err();
+        assert_eq!(error.code(), PROTOCOL_ERROR);
+        assert_eq!(error.stream_id(), Some(id));
+    }
+
+    #[test]
+    fn test_frames_split_to_lowered_max_frame_size() {
+        let max = 2 * MIN_FRAME_SIZE;
+        let mut http2 = server(Settings { max_frame_size: max, ..Settings::default() });
+        let mut encoder = hpack::Encoder::new();
+        let id = StreamIdentifier(1);
+        http2.apply(request(&mut encoder, 1, Flag::end_stream())).unwrap();
+
+        let value = String::from_utf8(vec![b'x'; max as usize]).unwrap();
+        let response = [HeaderField::new(":status", "200"), HeaderField::new("x-large", value)];
+        http2.send_headers(id, &response, false).unwrap();
+        http2.send_data(id, &vec![0u8; MIN_FRAME_SIZE as usize + 1], true).unwrap();
+
+        // Lowered after the frames above were queued at the larger size.
+        http2.apply(Frame::new(Flag::empty(), StreamIdentifier(0),
+                               Settings::default().payload())).unwrap();
+
+        let frames = written(&mut http2).into_iter()
+            .filter(|frame| frame.header.id == id)
+            .collect::<Vec<_>>();
+        assert!(frames.iter().all(|frame| frame.header.length <= MIN_FRAME_SIZE));
+
+        let mut block = Vec::new();
+        for frame in &frames[..2] {
+            match frame.payload {
+                Payload::Headers { block: ref part, .. } | Payload::Continuation(ref part) =>
+                    block.extend_from_slice(part),
+                ref payload => panic!("Expected a header block, got {:?}", payload)
+            }
+        }
+
+        assert!(!frames[0].header.flag.contains(Flag::end_headers()));
+        assert!(frames[1].header.flag.contains(Flag::end_headers()));
+        assert_eq!(hpack::Decoder::new().decode(&block).unwrap(), response.to_vec());
+        assert_eq!(data(&frames[2..]), vec![(vec![0; MIN_FRAME_SIZE as usize], false),
+                                             (vec![0], true)]);
+    }
+
+    #[test]
+    fn test_priority_on_idle_streams() {
+        let mut http2 = server(Settings::default());
+        for n in 0..IDLE_STREAMS as u32 + 10 {
+            http2.apply(priority(2 * n + 3, 0)).unwrap();
+        }
+
+        // No streams are created, and only so many priorities remembered.
+        let last = StreamIdentifier(2 * IDLE_STREAMS as u32 + 3);
+        assert_eq!(http2.tracked_streams(), 0);
+        assert!(http2.outgoing.tree.contains(StreamIdentifier(3)));
+        assert!(!http2.outgoing.tree.contains(last));
+
+        // Opening stream 5 skips stream 3, whose priority is forgotten.
+        let mut encoder = hpack::Encoder::new();
+        http2.apply(request(&mut encoder, 5, Flag::end_stream())).unwrap();
+        assert!(!http2.outgoing.tree.contains(StreamIdentifier(3)));
+        assert!(http2.outgoing.tree.contains(StreamIdentifier(5)));
+        assert_eq!(http2.tracked_streams(), 1);
+
+        let error = http2.apply(priority(last.0, last.0)).unwrap_err();
+        assert_eq!(error.code(), PROTOCOL_ERROR);
+        assert_eq!(error.stream_id(), Some(last));
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::{VecDeque, HashMap, HashSet};
+use std::boxed::FnBox;
+use std::{fmt, usize};
+
+use http::parser::{Frame, Payload, Kind, Flag, StreamIdentifier};
+use http::encoder::FrameEncoder;
+use http::hpack::HeaderField;
+use http::priority::{PriorityTree, UrgencyScheduler};
+use http::Http2;
+
+/// Frames waiting to be written to the peer.
+///
+/// Frames which affect the whole connection are written first, in the order
+/// they were queued. Frames on streams are written in order per stream,
+/// with streams scheduled according to the priority tree, or by urgency
+/// once extensible priorities are in use.
+#[derive(Debug, Default)]
+pub struct Outgoing {
+    // Frames on stream 0, and RST_STREAM and WINDOW_UPDATE frames, which
+    // must not wait behind DATA.
+    control: VecDeque<(Frame, WriteCallback)>,
+
+    // All other frames and header blocks, queued per stream.
+    streams: HashMap<StreamIdentifier, VecDeque<(Queued, WriteCallback)>>,
+
+    // Streams promised by a PUSH_PROMISE which has not been written yet.
+    // Nothing may be written on them until it is.
+    promised: HashSet<StreamIdentifier>,
+
+    // The stream of a header block which has been partly written. Nothing
+    // else may be written until its last CONTINUATION frame is.
+    continuing: Option<StreamIdentifier>,
+
+    /// The RFC 7540 priorities of all streams on the connection.
+    pub tree: PriorityTree,
+
+    /// The RFC 9218 priorities of all streams on the connection.
+    pub urgency: UrgencyScheduler,
+
+    /// Whether streams are scheduled using `urgency` rather than `tree`.
+    pub extensible: bool,
+
+    pub current: Option<(FrameEncoder, WriteCallback)>
+}
+
+/// A header list to be sent in HEADERS, or PUSH_PROMISE if it promises a
+/// stream.
+///
+/// Header blocks are only encoded once they are the next thing written on
+/// their stream, so that they reach the peer in the order they were
+/// encoded without a block waiting behind DATA holding up other streams.
+#[derive(Debug)]
+pub struct Block {
+    pub id: StreamIdentifier,
+    pub flag: Flag,
+    pub promised: Option<StreamIdentifier>,
+    pub fields: Vec<HeaderField>
+}
+
+#[derive(Debug)]
+enum Queued {
+    Frame(Frame),
+    Block(Block)
+}
+
+impl Outgoing {
+    /// Queue a frame for writing.
+    ///
+    /// The callback will be called when the frame has been fully written
+    /// to the socket.
+    pub fn enqueue<F>(&mut self, frame: Frame, cb: F)
+    where F: for<'a> FnBox<(&'a mut Http2,), Output=()> + Send + 'static {
+        let id = frame.header.id;
+        let cb = WriteCallback(Box::new(cb));
+
+        if id.0 == 0 {
+            return self.control.push_back((frame, cb))
+        }
+
+        match frame.header.kind {
+            // Nothing else may be sent on a stream after it is reset, except
+            // the rest of a header block which has been partly written.
+            Kind::Reset => {
+                let mut dropped = Vec::new();
+                let pending = match self.streams.get_mut(&id) {
+                    Some(queue) => {
+                        queue.retain(|&(ref queued, _)| match *queued {
+                            Queued::Frame(ref frame) => is_header_block(frame),
+                            Queued::Block(ref block) => {
+                                dropped.extend(block.promised);
+                                false
+                            }
+                        });
+                        !queue.is_empty()
+                    },
+                    None => false
+                };
+
+                // Streams whose promise is never sent can never be used.
+                for promised in dropped {
+                    self.promised.remove(&promised);
+                    self.streams.remove(&promised);
+                }
+
+                if pending {
+                    self.streams.get_mut(&id).unwrap().push_back((Queued::Frame(frame), cb))
+                } else {
+                    self.streams.remove(&id);
+                    self.control.push_back((frame, cb))
+                }
+            },
+            Kind::WindowUpdate => self.control.push_back((frame, cb)),
+            _ => self.push(id, Queued::Frame(frame), cb)
+        }
+    }
+
+    /// Queue a header block for writing, encoding it once it is written.
+    ///
+    /// The callback will be called when the last frame of the block has
+    /// been fully written to the socket.
+    pub fn enqueue_block<F>(&mut self, block: Block, cb: F)
+    where F: for<'a> FnBox<(&'a mut Http2,), Output=()> + Send + 'static {
+        if let Some(promised) = block.promised {
+            self.promised.insert(promised);
+        }
+
+        let id = block.id;
+        self.push(id, Queued::Block(block), WriteCallback(Box::new(cb)));
+    }
+
+    fn push(&mut self, id: StreamIdentifier, queued: Queued, cb: WriteCallback) {
+        self.tree.insert(id);
+        self.streams.entry(id).or_insert_with(VecDeque::new).push_back((queued, cb))
+    }
+
+    /// Dequeue the next frame for which `limit` returns the number of bytes
+    /// of it which can be written now, encoding header blocks into frames
+    /// with `encode` when they are next.
+    ///
+    /// Only the first frame queued on each stream is considered, so that
+    /// frames on a stream stay in order. DATA or header block frames
+    /// longer than their limit are split, and the rest stays first in the
+    /// queue of its stream.
+    ///
+    /// Note: ensure that the callback is called when the frame is written.
+    pub fn dequeue_where<F, E>(&mut self, mut limit: F,
+                               mut encode: E) -> Option<(Frame, WriteCallback)>
+    where F: FnMut(&Frame) -> Option<usize>, E: FnMut(Block) -> Vec<Frame> {
+        // A header block must be sent as a contiguous sequence of frames,
+        // see Section 4.3 of the spec.
+        if let Some(id) = self.continuing {
+            let length = match self.streams.get(&id).and_then(|queue| queue.front()) {
+                Some(&(Queued::Frame(ref frame), _)) => limit(frame).unwrap_or(usize::MAX),
+                _ => usize::MAX
+            };
+
+            return self.take(id, length, &mut encode)
+        }
+
+        if let Some(position) = self.control.iter()
+                .position(|&(ref frame, _)| limit(frame).is_some()) {
+            return self.control.remove(position)
+        }
+
+        let next = {
+            let streams = &self.streams;
+            let promised = &self.promised;
+            let ready = |id: StreamIdentifier| {
+                !promised.contains(&id) && streams.get(&id)
+                    .and_then(|queue| queue.front())
+                    .map_or(false, |&(ref queued, _)| match *queued {
+                        Queued::Frame(ref frame) => limit(frame).is_some(),
+                        Queued::Block(_) => true
+                    })
+            };
+
+            if self.extensible {
+                self.urgency.next(streams.keys().cloned(), ready)
+            } else {
+                self.tree.next(ready)
+            }
+        };
+
+        let id = match next {
+            Some(id) => id,
+            None => return None
+        };
+
+        let length = match self.streams.get(&id).and_then(|queue| queue.front()) {
+            Some(&(Queued::Frame(ref frame), _)) => limit(frame),
+            Some(&(Queued::Block(_), _)) => Some(usize::MAX),
+            None => None
+        };
+
+        length.and_then(|length| self.take(id, length, &mut encode))
+    }
+
+    // Take the first frame queued on a stream, or only its first `length`
+    // bytes if it is DATA or part of a header block which is longer.
+    fn take<E>(&mut self, id: StreamIdentifier, length: usize,
+               encode: &mut E) -> Option<(Frame, WriteCallback)>
+    where E: FnMut(Block) -> Vec<Frame> {
+        let (entry, empty) = match self.streams.get_mut(&id) {
+            Some(queue) => {
+                let block = match queue.front() {
+                    Some(&(Queued::Block(_), _)) => true,
+                    _ => false
+                };
+
+                if block {
+                    if let Some((Queued::Block(block), cb)) = queue.pop_front() {
+                        let mut frames = encode(block).into_iter().rev();
+
+                        // The callback is called once the whole block is written.
+                        if let Some(last) = frames.next() {
+                            queue.push_front((Queued::Frame(last), cb));
+                        }
+
+                        for frame in frames {
+                            queue.push_front((Queued::Frame(frame), part(id)));
+                        }
+                    }
+                }
+
+                let entry = match queue.pop_front() {
+                    Some((Queued::Frame(frame), cb)) => match split(frame, length) {
+                        (first, Some(rest)) => {
+                            queue.push_front((Queued::Frame(rest), cb));
+                            Some((first, part(id)))
+                        },
+                        (frame, None) => Some((frame, cb))
+                    },
+                    Some((Queued::Block(_), _)) => unreachable!("header block was not encoded"),
+                    None => None
+                };
+
+                (entry, queue.is_empty())
+            },
+            None => return None
+        };
+
+        if empty { self.streams.remove(&id); }
+
+        if let Some((ref frame, _)) = entry {
+            let length = frame.header.length as usize;
+
+            if is_header_block(frame) {
+                self.continuing = if frame.header.flag.contains(Flag::end_headers()) {
+                    None
+                } else {
+                    Some(id)
+                };
+            }
+
+            if let Payload::PushPromise { promised, .. } = frame.payload {
+                self.promised.remove(&promised);
+            }
+
+            if self.extensible {
+                self.urgency.charge(id, length);
+            } else {
+                self.tree.charge(id, length);
+            }
+        }
+
+        entry
+    }
+
+    /// Are there frames waiting to be written on a stream?
+    pub fn is_queued(&self, id: StreamIdentifier) -> bool {
+        self.streams.contains_key(&id)
+    }
+
+    /// Forget the priority of a stream which is no longer needed.
+    pub fn remove(&mut self, id: StreamIdentifier) {
+        self.tree.remove(id);
+        self.urgency.remove(id);
+    }
+
+    /// Are there any frames remaining to be encoded?
+    pub fn is_empty(&self) -> bool {
+        self.control.is_empty() && self.streams.is_empty() && self.current.is_none()
+    }
+}
+
+// The callback of a frame which is only part of what was queued.
+fn part(id: StreamIdentifier) -> WriteCallback {
+    WriteCallback(Box::new(move |_: &mut Http2| {
+        debug!("Wrote part of a frame on {:?}", id);
+    }))
+}
+
+// Split a frame longer than `length` into a frame of `length` bytes and
+// the rest.
+//
+// The rest of DATA keeps the flags of the original frame. A header block
+// frame is split into a frame without END_HEADERS and a CONTINUATION,
+// which ends the block if the original frame did, see Section 6.10 of
+// the spec.
+fn split(frame: Frame, length: usize) -> (Frame, Option<Frame>) {
+    let Frame { header, payload } = frame;
+
+    if header.length as usize <= length {
+        return (Frame { header: header, payload: payload }, None)
+    }
+
+    let mut flag = header.flag;
+    flag.remove(Flag::end_headers());
+
+    let mut rest = Flag::empty();
+    if header.flag.contains(Flag::end_headers()) { rest.insert(Flag::end_headers()) }
+
+    match payload {
+        Payload::Data(data) =>
+            (Frame::new(Flag::empty(), header.id, Payload::Data(data.slice_to(length))),
+             Some(Frame::new(header.flag, header.id, Payload::Data(data.slice_from(length))))),
+        Payload::Headers { priority, block } => {
+            // The priority takes 5 bytes before the header block.
+            let at = length.saturating_sub(priority.as_ref().map_or(0, |_| 5));
+
+            (Frame::new(flag, header.id, Payload::Headers {
+                priority: priority,
+                block: block.slice_to(at)
+             }),
+             Some(Frame::new(rest, header.id, Payload::Continuation(block.slice_from(at)))))
+        },
+        Payload::PushPromise { promised, block } => {
+            // The promised stream takes 4 bytes before the header block.
+            let at = length.saturating_sub(4);
+
+            (Frame::new(flag, header.id, Payload::PushPromise {
+                promised: promised,
+                block: block.slice_to(at)
+             }),
+             Some(Frame::new(rest, header.id, Payload::Continuation(block.slice_from(at)))))
+        },
+        Payload::Continuation(block) =>
+            (Frame::new(flag, header.id, Payload::Continuation(block.slice_to(length))),
+             Some(Frame::new(rest, header.id, Payload::Continuation(block.slice_from(length))))),
+        payload => (Frame { header: header, payload: payload }, None)
+    }
+}
+
+fn is_header_block(frame: &Frame) -> bool {
+    match frame.header.kind {
+        Kind::Headers | Kind::PushPromise | Kind::Continuation => true,
+        _ => false
+    }
+}
+
+pub struct WriteCallback(pub Box<for<'a> FnBox<(&'a mut Http2,), Output=()> + Send>);
+
+impl fmt::Debug for WriteCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Write Callback")
+    }
+}
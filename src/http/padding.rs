@@ -0,0 +1,87 @@
+use std::cmp;
+
+#[cfg(feature = "random")]
+use rand::{self, Rng};
+
+/// How DATA and HEADERS frames are padded, to obscure the length of their
+/// contents.
+///
+/// ```text
+/// Padding can be used to obscure the exact size of frame content and is provided to mitigate
+/// specific attacks within HTTP, for example, attacks where compressed content includes both
+/// attacker-controlled plaintext and secret data.
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PaddingPolicy {
+    /// Frames are not padded.
+    None,
+
+    /// Every frame is padded with this many bytes.
+    Fixed(u8),
+
+    /// Every frame is padded with a random number of bytes, up to this many.
+    ///
+    /// Only available with the `random` feature.
+    #[cfg(feature = "random")]
+    Random(u8),
+
+    /// Frames are padded so the length of their payload is a multiple of
+    /// this size, as far as 255 bytes of padding allow.
+    Bucket(u32)
+}
+
+impl Default for PaddingPolicy {
+    fn default() -> PaddingPolicy { PaddingPolicy::None }
+}
+
+impl PaddingPolicy {
+    /// The padding for a frame with a payload of `length` bytes, or None if
+    /// the frame should not be padded.
+    ///
+    /// At most `limit` bytes may be added to the payload, including the
+    /// byte holding the padding length.
+    pub fn padding(&self, length: usize, limit: usize) -> Option<u8> {
+        if limit == 0 { return None }
+
+        let padding = match *self {
+            PaddingPolicy::None => return None,
+            PaddingPolicy::Fixed(padding) => padding as usize,
+            #[cfg(feature = "random")]
+            PaddingPolicy::Random(max) =>
+                rand::thread_rng().gen_range(0, max as usize + 1),
+            PaddingPolicy::Bucket(0) => 0,
+            PaddingPolicy::Bucket(size) => {
+                let size = size as usize;
+                let padded = length + 1;
+                (size - padded % size) % size
+            }
+        };
+
+        Some(cmp::min(padding, cmp::min(limit - 1, 255)) as u8)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PaddingPolicy;
+
+    #[test]
+    fn test_padding() {
+        assert_eq!(PaddingPolicy::None.padding(100, 1000), None);
+        assert_eq!(PaddingPolicy::Fixed(10).padding(100, 1000), Some(10));
+        assert_eq!(PaddingPolicy::Fixed(10).padding(100, 5), Some(4));
+        assert_eq!(PaddingPolicy::Fixed(10).padding(100, 0), None);
+
+        assert_eq!(PaddingPolicy::Bucket(64).padding(100, 1000), Some(27));
+        assert_eq!(PaddingPolicy::Bucket(64).padding(127, 1000), Some(0));
+        assert_eq!(PaddingPolicy::Bucket(1024).padding(100, 1000), Some(255));
+    }
+
+    #[cfg(feature = "random")]
+    #[test]
+    fn test_random_padding() {
+        for _ in 0..100 {
+            assert!(PaddingPolicy::Random(8).padding(100, 1000).unwrap() <= 8);
+        }
+    }
+}
@@ -4,12 +4,17 @@ use std::{slice, mem};
 
 use util::TypedSlice;
 
+use http::error::{self, PROTOCOL_ERROR, FRAME_SIZE_ERROR};
+
 pub use http2parse::{
     FrameHeader, Priority, SizeIncrement,
     ErrorCode, Setting, StreamIdentifier,
     Flag, Kind
 };
 
+/// The frame type of PRIORITY_UPDATE, see Section 7.1 of RFC 9218.
+pub const PRIORITY_UPDATE: u8 = 0x10;
+
 #[derive(Debug, Clone)]
 pub struct Frame {
     pub header: FrameHeader,
@@ -38,6 +43,19 @@ impl Frame {
         Frame::parse(frame.header, buf.slice().slice_from(9)).unwrap()
     }
 
+    /// Create a new Frame, calculating the length of the header from the payload.
+    pub fn new(flag: Flag, id: StreamIdentifier, payload: Payload) -> Frame {
+        Frame {
+            header: FrameHeader {
+                length: payload.encoded_len() as u32,
+                kind: payload.kind(),
+                flag: flag,
+                id: id
+            },
+            payload: payload
+        }
+    }
+
     pub fn parse(header: FrameHeader, buf: Slice) -> Result<Frame> {
         let raw = try!(::http2parse::Frame::parse(header, &buf));
 
@@ -46,6 +64,20 @@ impl Frame {
             payload: Payload::convert(raw.payload, &buf)
         })
     }
+
+    /// Recognize the payload of a frame of an extension type.
+    ///
+    /// http2parse parses all frames of unknown types as Unregistered, so
+    /// the raw frame type must be supplied separately.
+    pub fn extension(self, kind: u8) -> Frame {
+        match (kind, self.payload) {
+            (PRIORITY_UPDATE, Payload::Unregistered(block)) => Frame {
+                header: self.header,
+                payload: Payload::PriorityUpdate(block)
+            },
+            (_, payload) => Frame { header: self.header, payload: payload }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -63,6 +95,37 @@ impl From<::http2parse::Error> for Error {
     }
 }
 
+impl Error {
+    /// The error to answer a frame which failed to parse with, as
+    /// classified by Sections 4.2 and 6 of the spec.
+    ///
+    /// Errors in a frame of a known type are given its `header`.
+    pub fn to_http(&self, header: Option<&FrameHeader>) -> error::Error {
+        use http2parse::Error as Raw;
+
+        let (code, message) = match *self {
+            Error::Parse(Raw::TooMuchPadding(_)) =>
+                (PROTOCOL_ERROR, "padding exceeds frame payload"),
+            Error::Parse(Raw::BadFlag(_)) => (PROTOCOL_ERROR, "invalid frame flags"),
+            Error::Parse(Raw::BadKind(_)) => (PROTOCOL_ERROR, "invalid frame type"),
+            Error::Parse(Raw::PartialSettingLength) =>
+                (FRAME_SIZE_ERROR, "SETTINGS length is not a multiple of 6"),
+            Error::Parse(Raw::InvalidPayloadLength) =>
+                (FRAME_SIZE_ERROR, "invalid frame length"),
+            Error::Parse(Raw::PayloadLengthTooShort) | Error::Parse(Raw::Short) |
+            Error::Incomplete => (FRAME_SIZE_ERROR, "frame too short")
+        };
+
+        match header {
+            // A PRIORITY frame of the wrong size only affects its stream,
+            // see Section 6.3 of the spec.
+            Some(&FrameHeader { kind: Kind::Priority, id, .. }) if code == FRAME_SIZE_ERROR =>
+                error::Error::stream(id, code, message),
+            _ => error::Error::connection(code, message)
+        }
+    }
+}
+
 pub type Result<T> = ::std::result::Result<T, Error>;
 
 #[derive(Debug, Clone)]
@@ -87,6 +150,12 @@ pub enum Payload {
     },
     WindowUpdate(SizeIncrement),
     Continuation(Slice),
+
+    /// A PRIORITY_UPDATE frame, as defined in RFC 9218.
+    ///
+    /// http2parse does not know this frame type, so the payload is left
+    /// unparsed.
+    PriorityUpdate(Slice),
     Unregistered(Slice)
 }
 
@@ -117,6 +186,7 @@ impl<'a> PartialEq<::http2parse::Payload<'a>> for Payload {
                 increment == increment1,
             (&P1::Continuation(ref block), P2::Continuation(block1)) =>
                 &**block == block1,
+            (&P1::PriorityUpdate(ref block), P2::Unregistered(block1)) |
             (&P1::Unregistered(ref block), P2::Unregistered(block1)) =>
                 &**block == block1,
             _ => false
@@ -136,6 +206,42 @@ impl Payload {
         }
     }
 
+    /// The Kind of frame this payload belongs in.
+    pub fn kind(&self) -> Kind {
+        match *self {
+            Payload::Data(_) => Kind::Data,
+            Payload::Headers { .. } => Kind::Headers,
+            Payload::Priority(_) => Kind::Priority,
+            Payload::Reset(_) => Kind::Reset,
+            Payload::Settings(_) => Kind::Settings,
+            Payload::PushPromise { .. } => Kind::PushPromise,
+            Payload::Ping(_) => Kind::Ping,
+            Payload::GoAway { .. } => Kind::GoAway,
+            Payload::WindowUpdate(_) => Kind::WindowUpdate,
+            Payload::Continuation(_) => Kind::Continuation,
+            Payload::PriorityUpdate(_) | Payload::Unregistered(_) => Kind::Unregistered
+        }
+    }
+
+    /// The number of bytes this payload will occupy when encoded.
+    pub fn encoded_len(&self) -> usize {
+        match *self {
+            Payload::Data(ref data) => data.len(),
+            Payload::Headers { ref priority, ref block } =>
+                priority.as_ref().map(|_| 5).unwrap_or(0) + block.len(),
+            Payload::Priority(_) => 5,
+            Payload::Reset(_) => 4,
+            Payload::Settings(ref settings) => settings.bytes().len(),
+            Payload::PushPromise { ref block, .. } => 4 + block.len(),
+            Payload::Ping(_) => 8,
+            Payload::GoAway { ref data, .. } => 8 + data.len(),
+            Payload::WindowUpdate(_) => 4,
+            Payload::Continuation(ref block) => block.len(),
+            Payload::PriorityUpdate(ref block) | Payload::Unregistered(ref block) =>
+                block.len()
+        }
+    }
+
     fn convert(raw: ::http2parse::Payload, buf: &Slice) -> Payload {
         use http2parse::Payload as Raw;
 
@@ -194,7 +300,9 @@ unsafe fn convert_slice<'a>(buf: &Slice, slice: &'a [u8]) -> Slice {
 
 #[cfg(test)]
 mod tests {
-    use super::convert_slice;
+    use super::{convert_slice, Frame, FrameHeader, Kind, Flag, StreamIdentifier};
+
+    use http::error::{PROTOCOL_ERROR, FRAME_SIZE_ERROR};
 
     use appendbuf::{AppendBuf, Slice};
 
@@ -212,6 +320,34 @@ mod tests {
         assert_eq!(b"ll", &*converted);
     }
 
+    #[test]
+    fn test_parse_error_codes() {
+        let header = |kind, length| FrameHeader {
+            length: length,
+            kind: kind,
+            flag: Flag::empty(),
+            id: StreamIdentifier(1)
+        };
+
+        // Padding as long as the payload is a protocol error.
+        let data = FrameHeader { flag: Flag::padded(), ..header(Kind::Data, 4) };
+        let error = Frame::parse(data, slice("\x04abc")).unwrap_err().to_http(Some(&data));
+        assert_eq!(error.code(), PROTOCOL_ERROR);
+        assert_eq!(error.stream_id(), None);
+
+        // PING must be 8 bytes long.
+        let ping = FrameHeader { id: StreamIdentifier(0), ..header(Kind::Ping, 4) };
+        let error = Frame::parse(ping, slice("ping")).unwrap_err().to_http(Some(&ping));
+        assert_eq!(error.code(), FRAME_SIZE_ERROR);
+        assert_eq!(error.stream_id(), None);
+
+        // PRIORITY must be 5 bytes long, but only its stream is affected.
+        let priority = header(Kind::Priority, 4);
+        let error = Frame::parse(priority, slice("abcd")).unwrap_err().to_http(Some(&priority));
+        assert_eq!(error.code(), FRAME_SIZE_ERROR);
+        assert_eq!(error.stream_id(), Some(StreamIdentifier(1)));
+    }
+
     #[cfg(feature = "random")]
     mod rand {
         use appendbuf::AppendBuf;
@@ -0,0 +1,522 @@
+use std::collections::HashMap;
+
+use byteorder::{ByteOrder, BigEndian};
+
+use http::parser::{Priority, StreamIdentifier};
+
+/// The weight given to streams without an explicit priority.
+pub const DEFAULT_WEIGHT: u16 = 16;
+
+// Scales the virtual time charged for each byte, so that small frames
+// on low weight streams still make progress.
+const STRIDE: u64 = 256;
+
+/// A stream dependency, as carried in HEADERS and PRIORITY frames.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Dependency {
+    pub id: StreamIdentifier,
+
+    /// The weight, between 1 and 256.
+    pub weight: u16,
+    pub exclusive: bool
+}
+
+impl Default for Dependency {
+    fn default() -> Dependency {
+        Dependency {
+            id: StreamIdentifier(0),
+            weight: DEFAULT_WEIGHT,
+            exclusive: false
+        }
+    }
+}
+
+impl From<Priority> for Dependency {
+    fn from(priority: Priority) -> Dependency {
+        let mut buf = [0; 5];
+        priority.encode(&mut buf);
+
+        // The weight is sent as one less than its value.
+        Dependency {
+            id: StreamIdentifier(BigEndian::read_u32(&buf[..4]) & 0x7fffffff),
+            weight: buf[4] as u16 + 1,
+            exclusive: buf[0] & 0x80 == 0x80
+        }
+    }
+}
+
+/// The stream dependency tree, as described in Section 5.3 of the spec.
+///
+/// Stream 0 is the root of the tree. Streams are scheduled so that a
+/// stream which can make progress is always preferred to its dependents,
+/// and siblings share bandwidth in proportion to their weights.
+#[derive(Debug)]
+pub struct PriorityTree {
+    nodes: HashMap<StreamIdentifier, Node>
+}
+
+#[derive(Debug)]
+struct Node {
+    parent: StreamIdentifier,
+    weight: u16,
+    children: Vec<StreamIdentifier>,
+
+    // The virtual time at which this stream was last scheduled, used to
+    // share bandwidth between siblings.
+    pass: u64
+}
+
+impl Default for PriorityTree {
+    fn default() -> PriorityTree { PriorityTree::new() }
+}
+
+impl PriorityTree {
+    pub fn new() -> PriorityTree {
+        let mut nodes = HashMap::new();
+        nodes.insert(StreamIdentifier(0), Node {
+            parent: StreamIdentifier(0),
+            weight: DEFAULT_WEIGHT,
+            children: Vec::new(),
+            pass: 0
+        });
+
+        PriorityTree { nodes: nodes }
+    }
+
+    /// Is this stream in the tree?
+    pub fn contains(&self, id: StreamIdentifier) -> bool {
+        self.nodes.contains_key(&id)
+    }
+
+    /// Add a stream to the tree with the default priority, if it is not
+    /// already present.
+    pub fn insert(&mut self, id: StreamIdentifier) {
+        if !self.contains(id) {
+            self.attach(id, Dependency::default());
+        }
+    }
+
+    /// Set the priority of a stream, adding it to the tree if needed.
+    ///
+    /// Implements the reprioritization rules of Section 5.3.3. A stream
+    /// may not depend on itself; callers must treat that as an error.
+    pub fn prioritize(&mut self, id: StreamIdentifier, dependency: Dependency) {
+        assert!(id != dependency.id, "Stream cannot depend on itself.");
+
+        // A dependency on a stream not in the tree gets the default priority.
+        let dependency = if self.contains(dependency.id) {
+            dependency
+        } else {
+            Dependency { weight: dependency.weight, ..Dependency::default() }
+        };
+
+        if !self.contains(id) {
+            return self.attach(id, dependency)
+        }
+
+        // If the new parent depends on this stream, it is first moved to
+        // depend on this stream's current parent.
+        if self.is_ancestor(id, dependency.id) {
+            let parent = self.nodes[&id].parent;
+            self.detach(dependency.id);
+            self.link(dependency.id, parent);
+        }
+
+        self.detach(id);
+        self.nodes.get_mut(&id).unwrap().weight = dependency.weight;
+        self.link(id, dependency.id);
+
+        if dependency.exclusive {
+            self.adopt(id, dependency.id);
+        }
+    }
+
+    /// Remove a stream from the tree.
+    ///
+    /// Its dependents are moved to its parent, sharing its weight in
+    /// proportion to their own, as described in Section 5.3.4.
+    pub fn remove(&mut self, id: StreamIdentifier) {
+        if id.0 == 0 || !self.contains(id) { return }
+
+        self.detach(id);
+        let node = self.nodes.remove(&id).unwrap();
+
+        let total = node.children.iter()
+            .map(|child| self.nodes[child].weight as u32)
+            .fold(0, |a, b| a + b);
+
+        for child in node.children {
+            {
+                let child = self.nodes.get_mut(&child).unwrap();
+                let weight = child.weight as u32 * node.weight as u32 / total;
+                child.weight = if weight < 1 { 1 } else { weight as u16 };
+            }
+
+            self.link(child, node.parent);
+        }
+    }
+
+    /// Select the next stream to send a frame on, out of the streams for
+    /// which `ready` returns true.
+    pub fn next<F>(&self, mut ready: F) -> Option<StreamIdentifier>
+    where F: FnMut(StreamIdentifier) -> bool {
+        self.select(StreamIdentifier(0), &mut ready)
+    }
+
+    /// Charge a stream and its ancestors for sending `length` bytes.
+    pub fn charge(&mut self, id: StreamIdentifier, length: usize) {
+        let mut current = id;
+
+        while current.0 != 0 {
+            let node = match self.nodes.get_mut(&current) {
+                Some(node) => node,
+                None => return
+            };
+
+            node.pass += (length as u64 + 1) * STRIDE / node.weight as u64;
+            current = node.parent;
+        }
+    }
+
+    fn select(&self, id: StreamIdentifier, ready: &mut FnMut(StreamIdentifier) -> bool)
+              -> Option<StreamIdentifier> {
+        // Dependent streams only get resources when their parent cannot
+        // make progress.
+        if id.0 != 0 && ready(id) {
+            return Some(id)
+        }
+
+        let mut children = self.nodes[&id].children.iter()
+            .map(|child| (self.nodes[child].pass, *child))
+            .collect::<Vec<_>>();
+        children.sort_by(|a, b| (a.0, (a.1).0).cmp(&(b.0, (b.1).0)));
+
+        for (_, child) in children {
+            if let Some(id) = self.select(child, ready) {
+                return Some(id)
+            }
+        }
+
+        None
+    }
+
+    fn attach(&mut self, id: StreamIdentifier, dependency: Dependency) {
+        self.nodes.insert(id, Node {
+            parent: dependency.id,
+            weight: dependency.weight,
+            children: Vec::new(),
+            pass: 0
+        });
+
+        self.link(id, dependency.id);
+
+        if dependency.exclusive {
+            self.adopt(id, dependency.id);
+        }
+    }
+
+    // Make all other children of parent depend on id instead.
+    fn adopt(&mut self, id: StreamIdentifier, parent: StreamIdentifier) {
+        let siblings = self.nodes[&parent].children.iter()
+            .cloned()
+            .filter(|&child| child != id)
+            .collect::<Vec<_>>();
+
+        for sibling in siblings {
+            self.detach(sibling);
+            self.link(sibling, id);
+        }
+    }
+
+    // Add id to parent's children. New children start at the same virtual
+    // time as their least served sibling, so they neither starve nor burst.
+    fn link(&mut self, id: StreamIdentifier, parent: StreamIdentifier) {
+        let pass = self.nodes[&parent].children.iter()
+            .map(|child| self.nodes[child].pass)
+            .min()
+            .unwrap_or(0);
+
+        self.nodes.get_mut(&parent).unwrap().children.push(id);
+
+        let node = self.nodes.get_mut(&id).unwrap();
+        node.parent = parent;
+        node.pass = pass;
+    }
+
+    // Remove id from its parent's children.
+    fn detach(&mut self, id: StreamIdentifier) {
+        let parent = self.nodes[&id].parent;
+        self.nodes.get_mut(&parent).unwrap().children.retain(|&child| child != id);
+    }
+
+    // Is ancestor an ancestor of id?
+    fn is_ancestor(&self, ancestor: StreamIdentifier, id: StreamIdentifier) -> bool {
+        let mut current = id;
+
+        while current.0 != 0 {
+            current = self.nodes[&current].parent;
+            if current == ancestor { return true }
+        }
+
+        false
+    }
+}
+
+/// The default urgency of a stream, see Section 4.1 of RFC 9218.
+pub const DEFAULT_URGENCY: u8 = 3;
+
+/// The lowest priority urgency level.
+pub const MAX_URGENCY: u8 = 7;
+
+/// A stream priority using the extensible priority scheme of RFC 9218.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Urgency {
+    /// The urgency level, from 0 (most urgent) to 7.
+    pub level: u8,
+
+    /// Whether the response can be processed incrementally, in which case
+    /// it shares bandwidth with other incremental responses of the same
+    /// urgency.
+    pub incremental: bool
+}
+
+impl Default for Urgency {
+    fn default() -> Urgency {
+        Urgency { level: DEFAULT_URGENCY, incremental: false }
+    }
+}
+
+impl Urgency {
+    /// Parse a priority field value, as sent in the `priority` header or
+    /// a PRIORITY_UPDATE frame.
+    ///
+    /// The value is a Structured Fields dictionary. Unknown or invalid
+    /// members are ignored, leaving the default values in place.
+    pub fn parse(value: &[u8]) -> Urgency {
+        let mut urgency = Urgency::default();
+
+        for member in value.split(|&b| b == b',') {
+            let member = trim(member);
+            let (key, value) = match member.iter().position(|&b| b == b'=') {
+                Some(position) => (&member[..position], Some(&member[position + 1..])),
+                None => (member, None)
+            };
+
+            // Parameters on a member are ignored.
+            let value = value.map(|value| {
+                trim(value.split(|&b| b == b';').next().unwrap_or(value))
+            });
+
+            if key == b"u" {
+                match value {
+                    Some(value) if value.len() == 1 &&
+                        value[0] >= b'0' && value[0] - b'0' <= MAX_URGENCY =>
+                            urgency.level = value[0] - b'0',
+                    value => debug!("Ignoring invalid urgency {:?}", value)
+                }
+            } else if key == b"i" {
+                match value {
+                    None => urgency.incremental = true,
+                    Some(value) if value == b"?1" => urgency.incremental = true,
+                    Some(value) if value == b"?0" => urgency.incremental = false,
+                    value => debug!("Ignoring invalid incremental {:?}", value)
+                }
+            }
+        }
+
+        urgency
+    }
+}
+
+fn trim(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|&b| b != b' ').unwrap_or(bytes.len());
+    let end = bytes.iter().rposition(|&b| b != b' ').map(|end| end + 1).unwrap_or(start);
+    &bytes[start..end]
+}
+
+/// Schedules streams by urgency, as described in Section 10 of RFC 9218.
+///
+/// More urgent streams are always preferred. Within an urgency level,
+/// non-incremental streams are sent one at a time in order of stream id,
+/// and incremental streams share bandwidth.
+#[derive(Debug, Default)]
+pub struct UrgencyScheduler {
+    // The urgency of each stream, and the bytes sent on it so far.
+    streams: HashMap<StreamIdentifier, (Urgency, u64)>
+}
+
+impl UrgencyScheduler {
+    pub fn new() -> UrgencyScheduler { UrgencyScheduler::default() }
+
+    /// The urgency of a stream, or the default if none was signalled.
+    pub fn get(&self, id: StreamIdentifier) -> Urgency {
+        self.streams.get(&id).map(|&(urgency, _)| urgency).unwrap_or(Urgency::default())
+    }
+
+    /// Has an urgency been signalled for this stream?
+    pub fn contains(&self, id: StreamIdentifier) -> bool {
+        self.streams.contains_key(&id)
+    }
+
+    /// Set the urgency of a stream.
+    pub fn set(&mut self, id: StreamIdentifier, urgency: Urgency) {
+        self.streams.entry(id).or_insert((urgency, 0)).0 = urgency;
+    }
+
+    /// Forget about a stream.
+    pub fn remove(&mut self, id: StreamIdentifier) {
+        self.streams.remove(&id);
+    }
+
+    /// Select the next stream to send a frame on, out of `streams` for
+    /// which `ready` returns true.
+    pub fn next<I, F>(&self, streams: I, mut ready: F) -> Option<StreamIdentifier>
+    where I: Iterator<Item=StreamIdentifier>, F: FnMut(StreamIdentifier) -> bool {
+        streams
+            .filter(|&id| ready(id))
+            .min_by_key(|&id| {
+                let (urgency, sent) = self.streams.get(&id).cloned()
+                    .unwrap_or((Urgency::default(), 0));
+
+                // Incremental streams are served after non-incremental ones,
+                // and the least served goes first.
+                if urgency.incremental {
+                    (urgency.level, true, sent, id.0)
+                } else {
+                    (urgency.level, false, 0, id.0)
+                }
+            })
+    }
+
+    /// Charge a stream for sending `length` bytes.
+    pub fn charge(&mut self, id: StreamIdentifier, length: usize) {
+        self.streams.entry(id).or_insert((Urgency::default(), 0)).1 += length as u64 + 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PriorityTree, Dependency, Urgency, UrgencyScheduler};
+    use http::parser::StreamIdentifier;
+
+    fn id(id: u32) -> StreamIdentifier { StreamIdentifier(id) }
+
+    fn dependency(on: u32, weight: u16, exclusive: bool) -> Dependency {
+        Dependency { id: id(on), weight: weight, exclusive: exclusive }
+    }
+
+    fn children(tree: &PriorityTree, parent: u32) -> Vec<u32> {
+        let mut children = tree.nodes[&id(parent)].children.iter()
+            .map(|child| child.0).collect::<Vec<_>>();
+        children.sort();
+        children
+    }
+
+    #[test]
+    fn test_parent_before_dependents() {
+        let mut tree = PriorityTree::new();
+        tree.insert(id(1));
+        tree.prioritize(id(3), dependency(1, 16, false));
+
+        assert_eq!(tree.next(|_| true), Some(id(1)));
+        assert_eq!(tree.next(|stream| stream != id(1)), Some(id(3)));
+        assert_eq!(tree.next(|_| false), None);
+    }
+
+    #[test]
+    fn test_weighted_siblings() {
+        let mut tree = PriorityTree::new();
+        tree.prioritize(id(1), dependency(0, 192, false));
+        tree.prioritize(id(3), dependency(0, 64, false));
+
+        let mut sent = [0, 0];
+        for _ in 0..400 {
+            let next = tree.next(|_| true).unwrap();
+            tree.charge(next, 1024);
+            sent[(next.0 / 2) as usize] += 1;
+        }
+
+        assert_eq!(sent, [300, 100]);
+    }
+
+    #[test]
+    fn test_exclusive() {
+        // Section 5.3.1, exclusive insertion of D under A.
+        let mut tree = PriorityTree::new();
+        tree.insert(id(1));
+        tree.prioritize(id(3), dependency(1, 16, false));
+        tree.prioritize(id(5), dependency(1, 16, false));
+        tree.prioritize(id(7), dependency(1, 16, true));
+
+        assert_eq!(children(&tree, 1), vec![7]);
+        assert_eq!(children(&tree, 7), vec![3, 5]);
+    }
+
+    #[test]
+    fn test_reprioritize_onto_dependent() {
+        // Section 5.3.3, moving A to depend on its descendant D.
+        let mut tree = PriorityTree::new();
+        tree.insert(id(1));
+        tree.prioritize(id(3), dependency(1, 16, false));
+        tree.prioritize(id(5), dependency(1, 16, false));
+        tree.prioritize(id(7), dependency(5, 16, false));
+        tree.prioritize(id(9), dependency(5, 16, false));
+        tree.prioritize(id(11), dependency(7, 16, false));
+
+        tree.prioritize(id(1), dependency(7, 16, true));
+
+        assert_eq!(children(&tree, 0), vec![7]);
+        assert_eq!(children(&tree, 7), vec![1]);
+        assert_eq!(children(&tree, 1), vec![3, 5, 11]);
+        assert_eq!(children(&tree, 5), vec![9]);
+    }
+
+    #[test]
+    fn test_remove_redistributes_weight() {
+        let mut tree = PriorityTree::new();
+        tree.prioritize(id(1), dependency(0, 32, false));
+        tree.prioritize(id(3), dependency(1, 30, false));
+        tree.prioritize(id(5), dependency(1, 10, false));
+
+        tree.remove(id(1));
+
+        assert_eq!(children(&tree, 0), vec![3, 5]);
+        assert_eq!(tree.nodes[&id(3)].weight, 24);
+        assert_eq!(tree.nodes[&id(5)].weight, 8);
+    }
+
+    #[test]
+    fn test_parse_urgency() {
+        assert_eq!(Urgency::parse(b""), Urgency { level: 3, incremental: false });
+        assert_eq!(Urgency::parse(b"u=5"), Urgency { level: 5, incremental: false });
+        assert_eq!(Urgency::parse(b"u=0, i"), Urgency { level: 0, incremental: true });
+        assert_eq!(Urgency::parse(b"i=?1,u=1"), Urgency { level: 1, incremental: true });
+        assert_eq!(Urgency::parse(b"u=2;foo=bar, i=?0"), Urgency { level: 2, incremental: false });
+
+        // Invalid and unknown members are ignored.
+        assert_eq!(Urgency::parse(b"u=8, x=1"), Urgency::default());
+        assert_eq!(Urgency::parse(b"u=a, i=1"), Urgency::default());
+    }
+
+    #[test]
+    fn test_urgency_scheduler() {
+        let mut scheduler = UrgencyScheduler::new();
+        scheduler.set(id(1), Urgency { level: 5, incremental: false });
+        scheduler.set(id(5), Urgency { level: 1, incremental: true });
+        scheduler.set(id(7), Urgency { level: 1, incremental: true });
+
+        let streams = || vec![id(1), id(3), id(5), id(7)].into_iter();
+
+        // Lower urgency levels go first, incremental streams take turns.
+        assert_eq!(scheduler.next(streams(), |_| true), Some(id(5)));
+        scheduler.charge(id(5), 100);
+        assert_eq!(scheduler.next(streams(), |_| true), Some(id(7)));
+        scheduler.charge(id(7), 100);
+        assert_eq!(scheduler.next(streams(), |_| true), Some(id(5)));
+
+        // Then the default urgency, then the least urgent.
+        let ready = |stream: super::StreamIdentifier| stream.0 < 5;
+        assert_eq!(scheduler.next(streams(), ready), Some(id(3)));
+        assert_eq!(scheduler.next(streams(), |stream| stream == id(1)), Some(id(1)));
+    }
+}
@@ -0,0 +1,160 @@
+use byteorder::{ByteOrder, BigEndian};
+
+use http::parser::{Payload, Setting};
+use http::error::{Error, Result, PROTOCOL_ERROR, FLOW_CONTROL_ERROR, FRAME_SIZE_ERROR};
+
+use util::{self, TypedSlice};
+
+// Setting identifiers, as defined in Section 6.5.2 of the spec.
+pub const HEADER_TABLE_SIZE: u16 = 0x1;
+pub const ENABLE_PUSH: u16 = 0x2;
+pub const MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const MAX_FRAME_SIZE: u16 = 0x5;
+pub const MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+// Defined in Section 2.1 of RFC 9218.
+pub const NO_RFC7540_PRIORITIES: u16 = 0x9;
+
+/// The largest legal flow control window.
+pub const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+/// The smallest value SETTINGS_MAX_FRAME_SIZE may take, also its default.
+pub const MIN_FRAME_SIZE: u32 = 1 << 14;
+
+/// The largest value SETTINGS_MAX_FRAME_SIZE may take.
+pub const MAX_FRAME_SIZE_LIMIT: u32 = (1 << 24) - 1;
+
+// Each setting is a 16-bit identifier followed by a 32-bit value.
+const SETTING_LENGTH: usize = 6;
+
+/// The typed values of all settings for one side of a connection.
+///
+/// Settings which have no limit by default are represented as `None`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Settings {
+    pub header_table_size: u32,
+    pub enable_push: bool,
+    pub max_concurrent_streams: Option<u32>,
+    pub initial_window_size: u32,
+    pub max_frame_size: u32,
+    pub max_header_list_size: Option<u32>,
+
+    /// Whether the RFC 7540 priority scheme is disabled in favor of the
+    /// extensible priorities of RFC 9218.
+    pub no_rfc7540_priorities: bool
+}
+
+impl Default for Settings {
+    /// The initial values of all settings, as defined in Section 6.5.2.
+    fn default() -> Settings {
+        Settings {
+            header_table_size: 4096,
+            enable_push: true,
+            max_concurrent_streams: None,
+            initial_window_size: 65535,
+            max_frame_size: MIN_FRAME_SIZE,
+            max_header_list_size: None,
+            no_rfc7540_priorities: false
+        }
+    }
+}
+
+impl Settings {
+    /// Update these settings with the contents of a SETTINGS frame.
+    ///
+    /// Settings are applied in order; unknown settings are ignored.
+    pub fn apply(&mut self, settings: &TypedSlice<Setting>) -> Result<()> {
+        for setting in settings.bytes().chunks(SETTING_LENGTH) {
+            if setting.len() != SETTING_LENGTH {
+                return Err(Error::connection(FRAME_SIZE_ERROR,
+                                             "SETTINGS length not a multiple of 6"))
+            }
+
+            try!(self.set(BigEndian::read_u16(&setting[..2]),
+                          BigEndian::read_u32(&setting[2..])));
+        }
+
+        Ok(())
+    }
+
+    /// Set the setting with the given identifier, validating the value.
+    pub fn set(&mut self, identifier: u16, value: u32) -> Result<()> {
+        match identifier {
+            HEADER_TABLE_SIZE => self.header_table_size = value,
+            ENABLE_PUSH => match value {
+                0 => self.enable_push = false,
+                1 => self.enable_push = true,
+                _ => return Err(Error::connection(PROTOCOL_ERROR, "invalid SETTINGS_ENABLE_PUSH"))
+            },
+            MAX_CONCURRENT_STREAMS => self.max_concurrent_streams = Some(value),
+            INITIAL_WINDOW_SIZE => {
+                if value > MAX_WINDOW_SIZE {
+                    return Err(Error::connection(FLOW_CONTROL_ERROR,
+                                                 "SETTINGS_INITIAL_WINDOW_SIZE too large"))
+                }
+
+                self.initial_window_size = value
+            },
+            MAX_FRAME_SIZE => {
+                if value < MIN_FRAME_SIZE || value > MAX_FRAME_SIZE_LIMIT {
+                    return Err(Error::connection(PROTOCOL_ERROR, "invalid SETTINGS_MAX_FRAME_SIZE"))
+                }
+
+                self.max_frame_size = value
+            },
+            MAX_HEADER_LIST_SIZE => self.max_header_list_size = Some(value),
+            NO_RFC7540_PRIORITIES => match value {
+                0 => self.no_rfc7540_priorities = false,
+                1 => self.no_rfc7540_priorities = true,
+                _ => return Err(Error::connection(PROTOCOL_ERROR,
+                                                  "invalid SETTINGS_NO_RFC7540_PRIORITIES"))
+            },
+            identifier => debug!("Ignoring unknown setting {:?} = {:?}", identifier, value)
+        }
+
+        Ok(())
+    }
+
+    /// Encode these settings as the payload of a SETTINGS frame.
+    ///
+    /// Settings which are unlimited or disabled extensions are omitted,
+    /// all others are sent explicitly.
+    pub fn payload(&self) -> Payload {
+        let mut settings = vec![
+            (HEADER_TABLE_SIZE, self.header_table_size),
+            (ENABLE_PUSH, self.enable_push as u32),
+            (INITIAL_WINDOW_SIZE, self.initial_window_size),
+            (MAX_FRAME_SIZE, self.max_frame_size)
+        ];
+
+        if let Some(max) = self.max_concurrent_streams {
+            settings.push((MAX_CONCURRENT_STREAMS, max));
+        }
+
+        if let Some(max) = self.max_header_list_size {
+            settings.push((MAX_HEADER_LIST_SIZE, max));
+        }
+
+        if self.no_rfc7540_priorities {
+            settings.push((NO_RFC7540_PRIORITIES, 1));
+        }
+
+        encode(&settings)
+    }
+
+    /// An empty SETTINGS payload, as used to acknowledge the peer's settings.
+    pub fn ack() -> Payload { encode(&[]) }
+}
+
+fn encode(settings: &[(u16, u32)]) -> Payload {
+    let mut buf = vec![0; settings.len() * SETTING_LENGTH];
+
+    for (&(identifier, value), chunk) in settings.iter()
+            .zip(buf.chunks_mut(SETTING_LENGTH)) {
+        BigEndian::write_u16(&mut chunk[..2], identifier);
+        BigEndian::write_u32(&mut chunk[2..], value);
+    }
+
+    Payload::Settings(unsafe { TypedSlice::new(util::slice(&buf)) })
+}
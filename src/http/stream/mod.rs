@@ -1,10 +1,12 @@
-use http::parser::{StreamIdentifier, Frame, Payload};
+use http::parser::{StreamIdentifier, Frame, Payload, Flag};
+
+pub use self::state::State;
 
-use self::state::State;
 use super::Http2;
-use super::error::{Error, Result};
+use super::flow::Window;
+use super::error::{Error, Result, PROTOCOL_ERROR, STREAM_CLOSED, INTERNAL_ERROR};
 
-mod state;
+pub mod state;
 
 /// An http2 "stream", as described in the spec:
 ///
@@ -24,71 +26,316 @@ mod state;
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Stream {
     id: StreamIdentifier,
-    state: State
+    state: State,
+
+    // Did we close this stream by sending RST_STREAM?
+    reset: bool,
+
+    // Have the initial header blocks been received and sent? Any later
+    // header block carries trailers. Interim (1xx) responses do not count,
+    // as the final response follows them.
+    received_headers: bool,
+    sent_headers: bool,
+
+    /// The number of bytes of DATA we may send on this stream.
+    pub send_window: Window,
+
+    /// The number of bytes of DATA the peer may send on this stream.
+    pub recv_window: Window,
+
+    /// Space in `recv_window` freed by consumed DATA, which has not yet
+    /// been returned to the peer.
+    pub unreleased: usize
 }
 
 impl Stream {
-    pub fn new(id: StreamIdentifier) -> Stream {
+    /// Create a new stream with the given initial flow control window sizes.
+    pub fn new(id: StreamIdentifier, send_window: u32, recv_window: u32) -> Stream {
         Stream {
             id: id,
-            state: State::default()
+            state: State::default(),
+            reset: false,
+            received_headers: false,
+            sent_headers: false,
+            send_window: Window::new(send_window),
+            recv_window: Window::new(recv_window),
+            unreleased: 0
         }
     }
 
-    pub fn apply(self, streams: &mut Http2, frame: Frame) -> Result<Self> {
-        let header = frame.header;
-        streams.outgoing.enqueue(frame.clone(), move |_: &mut Http2| {
-            debug!("Called back for writing frame {:?}", header);
-        });
+    pub fn id(&self) -> StreamIdentifier { self.id }
 
-        let header = frame.header;
-        let payload = frame.payload;
+    pub fn state(&self) -> State { self.state }
 
-        // FIXME: remove
-        return Ok(self);
+    /// Has the initial header block been received on this stream?
+    pub fn received_headers(&self) -> bool { self.received_headers }
 
-        match (self.state, payload) {
-            (State::Idle, Payload::Headers { priority, block }) => {
-                Ok(self)
-            },
+    /// Record that the header block just received was an interim (1xx)
+    /// response, so the next header block is still the response rather
+    /// than trailers, see Section 8.1 of the spec.
+    pub fn interim(&mut self) {
+        self.received_headers = false;
+    }
 
-            (State::ReservedLocal, Payload::Reset(error)) => {
-                Ok(self)
-            },
+    /// Close an idle stream without it ever being opened.
+    ///
+    /// ```text
+    /// The first use of a new stream identifier implicitly closes all streams in the "idle"
+    /// state that might have been initiated by that peer with a lower-valued stream identifier.
+    /// ```
+    pub fn close(&mut self) {
+        self.state = State::Closed;
+    }
 
-            (State::ReservedRemote, Payload::Headers { priority, block }) => {
-                Ok(self)
-            },
+    /// Reserve this stream for a server push, as a result of a PUSH_PROMISE
+    /// being sent (local) or received (remote) on another stream.
+    pub fn reserve(&mut self, local: bool) -> Result<()> {
+        if self.state != State::Idle {
+            return Err(Error::connection(PROTOCOL_ERROR, "promised stream is not idle"))
+        }
 
-            (State::ReservedRemote, Payload::Reset(error)) => {
-                Ok(self)
-            },
+        self.state = if local { State::ReservedLocal } else { State::ReservedRemote };
+        Ok(())
+    }
 
-            (State::Open, Payload::Headers { priority, block }) => {
-                Ok(self)
-            },
+    /// Transition the stream's state for a frame received from the peer.
+    ///
+    /// See Section 5.1 of the spec for the transitions and errors.
+    pub fn apply(&mut self, streams: &mut Http2, frame: Frame) -> Result<()> {
+        let end_stream = frame.header.flag.contains(Flag::end_stream());
+        let length = frame.header.length as usize;
 
-            (State::Open, Payload::Data(data)) => {
-                Ok(self)
-            },
+        let flow_controlled = match frame.payload {
+            Payload::Data(_) => true,
+            Payload::WindowUpdate(increment) if self.state != State::Closed => {
+                if increment.0 == 0 {
+                    return Err(Error::stream(self.id, PROTOCOL_ERROR,
+                                             "WINDOW_UPDATE with zero increment"))
+                }
 
-            (State::Open, Payload::Reset(error)) => {
-                Ok(self)
+                try!(self.send_window.increase(increment.0)
+                         .map_err(|e| e.on_stream(self.id)));
+                false
             },
-
-            (State::HalfClosedLocal, Payload::Reset(error)) => {
-                Ok(self)
+            // DATA queued before the stream closed may still be waiting for
+            // space in the window. There is nothing left to reset if the
+            // window overflows.
+            Payload::WindowUpdate(increment) => {
+                let _ = self.send_window.increase(increment.0);
+                false
             },
+            _ => false
+        };
 
-            (_, Payload::Priority(priority)) => {
-                Ok(self)
-            },
+        let headers = match frame.payload {
+            Payload::Headers { .. } => true,
+            _ => false
+        };
+
+        // Trailers must end the stream, see Section 8.1 of the spec.
+        if headers && self.received_headers && !end_stream && self.is_open_remote() {
+            return Err(Error::stream(self.id, PROTOCOL_ERROR, "trailers without END_STREAM"))
+        }
+
+        self.state = match (self.state, frame.payload) {
+            // PRIORITY can be received in any state, and frames of unknown
+            // types must be ignored.
+            (state, Payload::Priority(_)) |
+            (state, Payload::Unregistered(_)) => state,
+
+            (State::Idle, Payload::Headers { .. }) =>
+                if end_stream { State::HalfClosedRemote } else { State::Open },
+
+            (State::ReservedLocal, Payload::Reset(_)) => State::Closed,
+            (State::ReservedLocal, Payload::WindowUpdate(_)) => State::ReservedLocal,
+
+            (State::ReservedRemote, Payload::Headers { .. }) =>
+                if end_stream { State::Closed } else { State::HalfClosedLocal },
+            (State::ReservedRemote, Payload::Reset(_)) => State::Closed,
+
+            (State::Open, Payload::Reset(_)) => State::Closed,
+            (State::Open, Payload::Headers { .. }) |
+            (State::Open, Payload::Data(_)) =>
+                if end_stream { State::HalfClosedRemote } else { State::Open },
+            (State::Open, Payload::WindowUpdate(_)) => State::Open,
+
+            (State::HalfClosedLocal, Payload::Reset(_)) => State::Closed,
+            (State::HalfClosedLocal, Payload::Headers { .. }) |
+            (State::HalfClosedLocal, Payload::Data(_)) =>
+                if end_stream { State::Closed } else { State::HalfClosedLocal },
+            (State::HalfClosedLocal, Payload::WindowUpdate(_)) => State::HalfClosedLocal,
+
+            (State::HalfClosedRemote, Payload::Reset(_)) => State::Closed,
+            (State::HalfClosedRemote, Payload::WindowUpdate(_)) => State::HalfClosedRemote,
+            (State::HalfClosedRemote, _) =>
+                return Err(Error::stream(self.id, STREAM_CLOSED, "frame on half-closed stream")),
+
+            // WINDOW_UPDATE and RST_STREAM may arrive for a short period after
+            // the stream is closed, and must be ignored.
+            (State::Closed, Payload::WindowUpdate(_)) |
+            (State::Closed, Payload::Reset(_)) => State::Closed,
+
+            // After we reset a stream the peer may still have frames in flight.
+            (State::Closed, _) if self.reset => State::Closed,
+            (State::Closed, _) =>
+                return Err(Error::stream(self.id, STREAM_CLOSED, "frame on closed stream")),
 
             // Illegal state/frame combo.
-            (state, frame) => {
-                Err(Error::InvalidFrameTypeForStreamState)
+            (state, payload) => {
+                debug!("Received illegal frame {:?} in state {:?}", payload, state);
+                return Err(Error::connection(PROTOCOL_ERROR, "frame illegal in stream state"))
             }
+        };
+
+        if flow_controlled && !self.reset {
+            try!(self.recv_window.consume(length).map_err(|e| e.on_stream(self.id)));
         }
+
+        if headers { self.received_headers = true }
+
+        debug!("Stream {:?} is now {:?} after receiving {:?}",
+               self.id, self.state, frame.header);
+        Ok(())
+    }
+
+    /// Transition the stream's state for a frame we are sending.
+    pub fn send(&mut self, frame: &Frame) -> Result<()> {
+        let end_stream = frame.header.flag.contains(Flag::end_stream());
+
+        let headers = match frame.payload {
+            Payload::Headers { .. } => true,
+            _ => false
+        };
+
+        if headers && self.sent_headers && !end_stream && self.is_open_local() {
+            return Err(Error::stream(self.id, INTERNAL_ERROR,
+                                     "sent trailers without END_STREAM"))
+        }
+
+        self.state = match (self.state, &frame.payload) {
+            // CONTINUATION only continues a header block, which has already
+            // changed the state.
+            (state, &Payload::Priority(_)) |
+            (state, &Payload::Continuation(_)) => state,
+
+            // Any stream which is not closed may be reset.
+            (State::Closed, &Payload::Reset(_)) =>
+                return Err(Error::stream(self.id, STREAM_CLOSED, "stream already closed")),
+            (_, &Payload::Reset(_)) => {
+                self.reset = true;
+                State::Closed
+            },
+
+            (State::Idle, &Payload::Headers { .. }) =>
+                if end_stream { State::HalfClosedLocal } else { State::Open },
+
+            (State::ReservedLocal, &Payload::Headers { .. }) =>
+                if end_stream { State::Closed } else { State::HalfClosedRemote },
+
+            (State::ReservedRemote, &Payload::WindowUpdate(_)) => State::ReservedRemote,
+
+            (State::Open, &Payload::Headers { .. }) |
+            (State::Open, &Payload::Data(_)) =>
+                if end_stream { State::HalfClosedLocal } else { State::Open },
+            (State::Open, &Payload::PushPromise { .. }) |
+            (State::Open, &Payload::WindowUpdate(_)) => State::Open,
+
+            (State::HalfClosedLocal, &Payload::WindowUpdate(_)) => State::HalfClosedLocal,
+
+            (State::HalfClosedRemote, &Payload::Headers { .. }) |
+            (State::HalfClosedRemote, &Payload::Data(_)) =>
+                if end_stream { State::Closed } else { State::HalfClosedRemote },
+            (State::HalfClosedRemote, &Payload::PushPromise { .. }) => State::HalfClosedRemote,
+
+            (state, payload) => {
+                error!("Tried to send illegal frame {:?} in state {:?}", payload, state);
+                return Err(Error::stream(self.id, INTERNAL_ERROR,
+                                         "sent frame illegal in stream state"))
+            }
+        };
+
+        if headers { self.sent_headers = true }
+
+        debug!("Stream {:?} is now {:?} after sending {:?}",
+               self.id, self.state, frame.header);
+        Ok(())
+    }
+
+    // Can the peer still send frames on this stream?
+    fn is_open_remote(&self) -> bool {
+        self.state == State::Open || self.state == State::HalfClosedLocal
+    }
+
+    // Can we still send frames on this stream?
+    fn is_open_local(&self) -> bool {
+        self.state == State::Open || self.state == State::HalfClosedRemote
     }
 }
 
+#[cfg(test)]
+mod test {
+    use http::parser::{StreamIdentifier, Frame, Payload, Flag};
+    use http::error::PROTOCOL_ERROR;
+    use http::Http2;
+    use util;
+
+    use super::{Stream, State};
+
+    fn headers(flag: Flag) -> Frame {
+        Frame::new(flag | Flag::end_headers(), StreamIdentifier(1), Payload::Headers {
+            priority: None,
+            block: util::slice(&[])
+        })
+    }
+
+    fn data(flag: Flag) -> Frame {
+        Frame::new(flag, StreamIdentifier(1), Payload::Data(util::slice(b"body")))
+    }
+
+    #[test]
+    fn test_trailers() {
+        let mut http2 = Http2::new();
+        let mut stream = Stream::new(StreamIdentifier(1), 65535, 65535);
+
+        stream.apply(&mut http2, headers(Flag::empty())).unwrap();
+        stream.apply(&mut http2, data(Flag::empty())).unwrap();
+        stream.apply(&mut http2, headers(Flag::end_stream())).unwrap();
+        assert_eq!(stream.state(), State::HalfClosedRemote);
+
+        stream.send(&headers(Flag::empty())).unwrap();
+        stream.send(&data(Flag::empty())).unwrap();
+        stream.send(&headers(Flag::end_stream())).unwrap();
+        assert_eq!(stream.state(), State::Closed);
+    }
+
+    #[test]
+    fn test_trailers_must_end_stream() {
+        let mut http2 = Http2::new();
+        let mut stream = Stream::new(StreamIdentifier(1), 65535, 65535);
+
+        stream.apply(&mut http2, headers(Flag::empty())).unwrap();
+        stream.apply(&mut http2, data(Flag::empty())).unwrap();
+
+        let error = stream.apply(&mut http2, headers(Flag::empty())).unwrap_err();
+        assert_eq!(error.code(), PROTOCOL_ERROR);
+        assert_eq!(error.stream_id(), Some(StreamIdentifier(1)));
+
+        stream.send(&headers(Flag::empty())).unwrap();
+        assert!(stream.send(&headers(Flag::empty())).is_err());
+    }
+
+    #[test]
+    fn test_interim_response() {
+        let mut http2 = Http2::new();
+        let mut stream = Stream::new(StreamIdentifier(1), 65535, 65535);
+        stream.send(&headers(Flag::end_stream())).unwrap();
+
+        stream.apply(&mut http2, headers(Flag::empty())).unwrap();
+        stream.interim();
+        assert!(!stream.received_headers());
+
+        stream.apply(&mut http2, headers(Flag::empty())).unwrap();
+        stream.apply(&mut http2, data(Flag::end_stream())).unwrap();
+        assert_eq!(stream.state(), State::Closed);
+    }
+}
@@ -0,0 +1,271 @@
+//! Validation of received header lists, as described in Section 8.1.2 of
+//! the spec.
+//!
+//! A request or response which fails validation is malformed, and must be
+//! treated as a stream error of type PROTOCOL_ERROR.
+
+use http::hpack::HeaderField;
+
+/// Header fields which are specific to a single HTTP/1.x connection, and
+/// must not appear in HTTP/2, see Section 8.1.2.2 of the spec.
+const CONNECTION_SPECIFIC: &'static [&'static [u8]] = &[
+    b"connection",
+    b"keep-alive",
+    b"proxy-connection",
+    b"transfer-encoding",
+    b"upgrade"
+];
+
+/// The pseudo-headers defined for requests, see Section 8.1.2.3 of the spec.
+const REQUEST_PSEUDO_HEADERS: &'static [&'static [u8]] = &[
+    b":method",
+    b":scheme",
+    b":authority",
+    b":path"
+];
+
+/// Check the header list of a request.
+///
+/// ```text
+/// All HTTP/2 requests MUST include exactly one valid value for the ":method", ":scheme", and
+/// ":path" pseudo-header fields, unless it is a CONNECT request. An HTTP request that omits
+/// mandatory pseudo-header fields is malformed.
+/// ```
+pub fn request(fields: &[HeaderField]) -> Result<(), &'static str> {
+    let mut seen = [false; 4];
+    let mut regular = false;
+
+    for field in fields {
+        if is_pseudo(field) {
+            // All pseudo-header fields MUST appear in the header block before
+            // regular header fields, see Section 8.1.2.1 of the spec.
+            if regular { return Err("pseudo-header after regular header") }
+
+            let index = match REQUEST_PSEUDO_HEADERS.iter()
+                                  .position(|name| *name == &*field.name) {
+                Some(index) => index,
+                None => return Err("unknown pseudo-header in request")
+            };
+
+            if seen[index] { return Err("duplicate pseudo-header") }
+            seen[index] = true;
+        } else {
+            regular = true;
+            try!(regular_field(field));
+        }
+    }
+
+    // CONNECT requests have only :method and :authority, see Section 8.3
+    // of the spec.
+    let connect = fields.iter().any(|field| {
+        &*field.name == b":method" && &*field.value == b"CONNECT"
+    });
+
+    if connect {
+        if !seen[2] { return Err("missing :authority in CONNECT request") }
+        if seen[1] || seen[3] { return Err(":scheme or :path in CONNECT request") }
+        return Ok(())
+    }
+
+    if !seen[0] { return Err("missing :method") }
+    if !seen[1] { return Err("missing :scheme") }
+    if !seen[3] { return Err("missing :path") }
+
+    if fields.iter().any(|field| &*field.name == b":path" && field.value.is_empty()) {
+        return Err("empty :path")
+    }
+
+    Ok(())
+}
+
+/// Check the header list of a response, which must have exactly one
+/// `:status` pseudo-header and no others, see Section 8.1.2.4 of the spec.
+pub fn response(fields: &[HeaderField]) -> Result<(), &'static str> {
+    let mut status = false;
+    let mut regular = false;
+
+    for field in fields {
+        if is_pseudo(field) {
+            if regular { return Err("pseudo-header after regular header") }
+            if &*field.name != b":status" { return Err("unknown pseudo-header in response") }
+            if status { return Err("duplicate pseudo-header") }
+
+            // HTTP/2 removes support for the 101 (Switching Protocols)
+            // informational status code, see Section 8.1.1 of the spec.
+            if &*field.value == b"101" { return Err("101 response") }
+            status = true;
+        } else {
+            regular = true;
+            try!(regular_field(field));
+        }
+    }
+
+    if !status { return Err("missing :status") }
+    Ok(())
+}
+
+/// Is this the header list of an interim (1xx) response, which is followed
+/// by the final response, see Section 8.1 of the spec?
+pub fn interim(fields: &[HeaderField]) -> bool {
+    fields.iter().any(|field| &*field.name == b":status" && field.value.first() == Some(&b'1'))
+}
+
+/// Check the header list of trailers, which must not contain any
+/// pseudo-headers.
+pub fn trailers(fields: &[HeaderField]) -> Result<(), &'static str> {
+    for field in fields {
+        if is_pseudo(field) { return Err("pseudo-header in trailers") }
+        try!(regular_field(field));
+    }
+
+    Ok(())
+}
+
+/// Concatenate the cookie header fields of a header list into one field,
+/// as described in Section 8.1.2.5 of the spec:
+///
+/// ```text
+/// If there are multiple Cookie header fields after decompression, these MUST be concatenated
+/// into a single octet string using the two-octet delimiter of 0x3B, 0x20 (the ASCII string
+/// "; ") before being passed into a non-HTTP/2 context.
+/// ```
+pub fn join_cookies(fields: Vec<HeaderField>) -> Vec<HeaderField> {
+    let mut cookie: Option<HeaderField> = None;
+    let mut joined = Vec::with_capacity(fields.len());
+
+    for field in fields {
+        if &*field.name != b"cookie" {
+            joined.push(field);
+            continue
+        }
+
+        match cookie {
+            Some(ref mut cookie) => {
+                cookie.value.extend_from_slice(b"; ");
+                cookie.value.extend_from_slice(&field.value);
+                cookie.sensitive = cookie.sensitive || field.sensitive;
+            },
+            None => cookie = Some(field)
+        }
+    }
+
+    joined.extend(cookie);
+    joined
+}
+
+fn is_pseudo(field: &HeaderField) -> bool {
+    field.name.first() == Some(&b':')
+}
+
+// Check a field which is not a pseudo-header.
+fn regular_field(field: &HeaderField) -> Result<(), &'static str> {
+    // Header field names MUST be converted to lowercase prior to their
+    // encoding in HTTP/2, see Section 8.1.2 of the spec.
+    if field.name.iter().any(|byte| b'A' <= *byte && *byte <= b'Z') {
+        return Err("uppercase header field name")
+    }
+
+    if CONNECTION_SPECIFIC.iter().any(|name| *name == &*field.name) {
+        return Err("connection-specific header field")
+    }
+
+    // The only exception to this is the TE header field, which MAY be
+    // present in an HTTP/2 request; when it is, it MUST NOT contain any
+    // value other than "trailers".
+    if &*field.name == b"te" && &*field.value != b"trailers" {
+        return Err("TE header field other than trailers")
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use http::hpack::HeaderField;
+
+    use super::{request, response, interim, trailers, join_cookies};
+
+    fn fields(list: &[(&str, &str)]) -> Vec<HeaderField> {
+        list.iter().map(|&(name, value)| HeaderField::new(name, value)).collect()
+    }
+
+    #[test]
+    fn test_valid_request() {
+        assert_eq!(request(&fields(&[
+            (":method", "GET"), (":scheme", "https"), (":authority", "example.com"),
+            (":path", "/"), ("accept", "*/*"), ("te", "trailers")
+        ])), Ok(()));
+
+        assert_eq!(request(&fields(&[
+            (":method", "CONNECT"), (":authority", "example.com:443")
+        ])), Ok(()));
+    }
+
+    #[test]
+    fn test_pseudo_headers() {
+        assert!(request(&fields(&[(":scheme", "https"), (":path", "/")])).is_err());
+        assert!(request(&fields(&[(":method", "GET"), (":path", "/")])).is_err());
+        assert!(request(&fields(&[(":method", "GET"), (":scheme", "https")])).is_err());
+        assert!(request(&fields(&[
+            (":method", "GET"), (":scheme", "https"), (":path", "")
+        ])).is_err());
+        assert!(request(&fields(&[
+            (":method", "GET"), (":method", "POST"), (":scheme", "https"), (":path", "/")
+        ])).is_err());
+        assert!(request(&fields(&[
+            (":method", "GET"), (":scheme", "https"), ("accept", "*/*"), (":path", "/")
+        ])).is_err());
+        assert!(request(&fields(&[
+            (":method", "GET"), (":scheme", "https"), (":path", "/"), (":status", "200")
+        ])).is_err());
+        assert!(request(&fields(&[
+            (":method", "CONNECT"), (":authority", "example.com:443"), (":path", "/")
+        ])).is_err());
+    }
+
+    #[test]
+    fn test_regular_headers() {
+        let request_with = |name: &str, value: &str| {
+            request(&fields(&[
+                (":method", "GET"), (":scheme", "https"), (":path", "/"), (name, value)
+            ]))
+        };
+
+        assert!(request_with("Accept", "*/*").is_err());
+        assert!(request_with("connection", "keep-alive").is_err());
+        assert!(request_with("transfer-encoding", "chunked").is_err());
+        assert!(request_with("te", "gzip").is_err());
+    }
+
+    #[test]
+    fn test_response() {
+        assert_eq!(response(&fields(&[(":status", "200"), ("server", "transfer")])), Ok(()));
+        assert!(response(&fields(&[("server", "transfer")])).is_err());
+        assert!(response(&fields(&[(":status", "200"), (":status", "204")])).is_err());
+        assert!(response(&fields(&[(":status", "200"), (":path", "/")])).is_err());
+        assert!(response(&fields(&[("server", "transfer"), (":status", "200")])).is_err());
+        assert!(response(&fields(&[(":status", "101")])).is_err());
+    }
+
+    #[test]
+    fn test_interim() {
+        assert!(interim(&fields(&[(":status", "103"), ("link", "</style.css>")])));
+        assert!(!interim(&fields(&[(":status", "200")])));
+    }
+
+    #[test]
+    fn test_trailers() {
+        assert_eq!(trailers(&fields(&[("grpc-status", "0")])), Ok(()));
+        assert!(trailers(&fields(&[(":status", "200")])).is_err());
+        assert!(trailers(&fields(&[("Grpc-Status", "0")])).is_err());
+    }
+
+    #[test]
+    fn test_join_cookies() {
+        let joined = join_cookies(fields(&[
+            ("cookie", "a=b"), ("accept", "*/*"), ("cookie", "c=d"), ("cookie", "e=f")
+        ]));
+
+        assert_eq!(joined, fields(&[("accept", "*/*"), ("cookie", "a=b; c=d; e=f")]));
+    }
+}
@@ -12,18 +12,22 @@ extern crate eventual;
 extern crate appendbuf;
 extern crate byteorder;
 
+#[cfg(feature = "random")]
+extern crate rand;
+
 #[macro_use]
 extern crate log;
 
-#[cfg(all(test, feature = "random"))]
-extern crate rand;
-
 pub use eventual::{Future, Complete, Stream, Sender};
 
 pub use error::{Result, Error};
+pub use message::{Request, Response, Body, Headers, Push};
 
+/// Handles requests received by the runtime.
+///
+/// Handlers are run on the runtime's `Executor`.
 pub trait Handler: Send + Sync + 'static {
-    fn handle(&self);
+    fn handle(&self, request: Request) -> Future<Response, Error>;
 }
 
 pub mod prelude {
@@ -33,6 +37,9 @@ pub mod prelude {
 
 pub mod http;
 
+/// HTTP requests and responses, as seen by a Handler.
+pub mod message;
+
 /// Transfer's Error type and associated impls.
 pub mod error;
 
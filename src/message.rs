@@ -0,0 +1,315 @@
+use std::time::Duration;
+use std::fmt;
+
+use appendbuf::Slice;
+
+use http::hpack::HeaderField;
+use http::parser::StreamIdentifier;
+use rt::connection::{Connection, Remote};
+
+use prelude::*;
+
+/// The header fields of a request or response, excluding pseudo-headers.
+pub type Headers = Vec<HeaderField>;
+
+/// An HTTP request received from a client, or sent by a `Client`.
+pub struct Request {
+    pub method: String,
+    pub scheme: String,
+    pub authority: Option<String>,
+    pub path: String,
+    pub headers: Headers,
+
+    /// The DATA received on the request's stream, which ends when the
+    /// client sends END_STREAM and fails if the stream is reset.
+    pub body: Stream<Slice, Error>,
+
+    /// The trailers sent after the body, or an empty list if the client
+    /// sent none.
+    pub trailers: Future<Headers, Error>,
+
+    /// Pushes responses to the client along with this request's response.
+    ///
+    /// Pushes on requests which were not received from a client are dropped.
+    pub push: Push
+}
+
+/// Starts server pushes associated with a request, see Section 8.2 of the
+/// spec.
+#[derive(Clone)]
+pub struct Push {
+    remote: Option<Remote>,
+    parent: StreamIdentifier,
+    scheme: String,
+    authority: Option<String>
+}
+
+/// An HTTP response to be sent to a client, or received by a `Client`.
+pub struct Response {
+    pub status: u16,
+    pub headers: Headers,
+    pub body: Body,
+
+    /// Trailers sent once the body is complete.
+    pub trailers: Option<Future<Headers, Error>>,
+
+    /// How many times a `Pool` retried the request before receiving this
+    /// response, because the server did not process it.
+    pub retries: u32
+}
+
+/// The body of a request or response.
+pub enum Body {
+    /// No body at all.
+    Empty,
+
+    /// A body which is available all at once.
+    Buffer(Vec<u8>),
+
+    /// A body which arrives in chunks.
+    ///
+    /// When sending, each chunk is only pulled once the previous one has
+    /// been written, so the body is never buffered in full.
+    Stream(Stream<Vec<u8>, Error>)
+}
+
+impl Request {
+    /// A request to send with a `Client`, with no headers, an empty body
+    /// and no trailers.
+    pub fn new(method: &str, path: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            scheme: "http".to_string(),
+            authority: None,
+            path: path.to_string(),
+            headers: Vec::new(),
+            body: Stream::empty(),
+            trailers: Future::of(Vec::new()),
+            push: Push::none()
+        }
+    }
+
+    /// Build a Request from a decoded header list, as received in a
+    /// HEADERS frame.
+    ///
+    /// The pseudo-headers are described in Section 8.1.2.3 of the spec.
+    pub fn from_fields(fields: Vec<HeaderField>, body: Stream<Slice, Error>,
+                       trailers: Future<Headers, Error>, mut push: Push)
+                       -> ::std::result::Result<Request, &'static str> {
+        let mut method = None;
+        let mut scheme = None;
+        let mut authority = None;
+        let mut path = None;
+        let mut headers = Vec::with_capacity(fields.len());
+
+        for field in fields {
+            let slot = if &*field.name == b":method" {
+                &mut method
+            } else if &*field.name == b":scheme" {
+                &mut scheme
+            } else if &*field.name == b":authority" {
+                &mut authority
+            } else if &*field.name == b":path" {
+                &mut path
+            } else {
+                headers.push(field);
+                continue
+            };
+
+            *slot = Some(try!(String::from_utf8(field.value)
+                .map_err(|_| "pseudo-header is not valid UTF-8")));
+        }
+
+        let scheme = try!(scheme.ok_or("missing :scheme"));
+        push.scheme = scheme.clone();
+        push.authority = authority.clone();
+
+        Ok(Request {
+            method: try!(method.ok_or("missing :method")),
+            scheme: scheme,
+            authority: authority,
+            path: try!(path.ok_or("missing :path")),
+            headers: headers,
+            body: body,
+            trailers: trailers,
+            push: push
+        })
+    }
+
+    /// The header list to send for this request, including the
+    /// pseudo-headers.
+    pub fn fields(&self) -> Vec<HeaderField> {
+        let mut fields = Vec::with_capacity(self.headers.len() + 4);
+        fields.push(HeaderField::new(&b":method"[..], self.method.clone().into_bytes()));
+        fields.push(HeaderField::new(&b":scheme"[..], self.scheme.clone().into_bytes()));
+
+        if let Some(ref authority) = self.authority {
+            fields.push(HeaderField::new(&b":authority"[..], authority.clone().into_bytes()));
+        }
+
+        fields.push(HeaderField::new(&b":path"[..], self.path.clone().into_bytes()));
+        fields.extend(self.headers.iter().cloned());
+        fields
+    }
+
+    /// Send a PING to the client this request was received from.
+    ///
+    /// The Future resolves to the round trip time once the client
+    /// acknowledges the PING, and is aborted if the connection closes first
+    /// or the request was not received from a client.
+    pub fn ping(&self) -> Future<Duration, Error> {
+        let (complete, rtt) = Future::pair();
+
+        if let Some(ref remote) = self.push.remote {
+            let _ = remote.run(move |connection: &mut Connection| {
+                connection.ping(complete)
+            });
+        }
+
+        rtt
+    }
+}
+
+impl Push {
+    pub fn new(remote: Remote, parent: StreamIdentifier) -> Push {
+        Push {
+            remote: Some(remote),
+            parent: parent,
+            scheme: String::new(),
+            authority: None
+        }
+    }
+
+    /// A Push for a request which was not received from a client, which
+    /// drops all pushes.
+    pub fn none() -> Push {
+        Push {
+            remote: None,
+            parent: StreamIdentifier(0),
+            scheme: String::new(),
+            authority: None
+        }
+    }
+
+    /// Push `response` as the response to a GET request for `path`, with
+    /// the same scheme and authority as the original request.
+    ///
+    /// Pushes the client does not accept, because it disabled push or has
+    /// too many pushed streams open, are dropped.
+    pub fn push(&self, path: &str, headers: Headers,
+                response: Future<Response, Error>) -> Result<()> {
+        let mut fields = vec![
+            HeaderField::new(&b":method"[..], &b"GET"[..]),
+            HeaderField::new(&b":scheme"[..], self.scheme.clone().into_bytes())
+        ];
+
+        if let Some(ref authority) = self.authority {
+            fields.push(HeaderField::new(&b":authority"[..], authority.clone().into_bytes()));
+        }
+
+        fields.push(HeaderField::new(&b":path"[..], path.to_string().into_bytes()));
+        fields.extend(headers);
+
+        let remote = match self.remote {
+            Some(ref remote) => remote,
+            None => return Ok(())
+        };
+
+        let parent = self.parent;
+        remote.run(move |connection: &mut Connection| {
+            connection.push(parent, fields, response)
+        })
+    }
+}
+
+impl fmt::Debug for Push {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Push {{ parent: {:?} }}", self.parent)
+    }
+}
+
+impl Response {
+    /// A response with the given status, no headers and an empty body.
+    pub fn new(status: u16) -> Response {
+        Response {
+            status: status,
+            headers: Vec::new(),
+            body: Body::Empty,
+            trailers: None,
+            retries: 0
+        }
+    }
+
+    /// Build a Response from a decoded header list, as received in a
+    /// HEADERS frame.
+    pub fn from_fields(fields: Vec<HeaderField>, body: Body,
+                       trailers: Option<Future<Headers, Error>>)
+                       -> ::std::result::Result<Response, &'static str> {
+        let mut status = None;
+        let mut headers = Vec::with_capacity(fields.len());
+
+        for field in fields {
+            if &*field.name != b":status" {
+                headers.push(field);
+                continue
+            }
+
+            // The status code is always three digits, see Section 8.1.2.4.
+            if field.value.len() != 3 { return Err("invalid :status") }
+            status = Some(try!(::std::str::from_utf8(&field.value).ok()
+                .and_then(|status| status.parse().ok())
+                .ok_or("invalid :status")));
+        }
+
+        Ok(Response {
+            status: try!(status.ok_or("missing :status")),
+            headers: headers,
+            body: body,
+            trailers: trailers,
+            retries: 0
+        })
+    }
+
+    /// The header list to send for this response, including the `:status`
+    /// pseudo-header.
+    pub fn fields(&self) -> Vec<HeaderField> {
+        let mut fields = Vec::with_capacity(self.headers.len() + 1);
+        fields.push(HeaderField::new(&b":status"[..], self.status.to_string().into_bytes()));
+        fields.extend(self.headers.iter().cloned());
+        fields
+    }
+}
+
+impl fmt::Debug for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Request")
+            .field("method", &self.method)
+            .field("scheme", &self.scheme)
+            .field("authority", &self.authority)
+            .field("path", &self.path)
+            .field("headers", &self.headers)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .field("body", &self.body)
+            .field("trailers", &self.trailers.is_some())
+            .field("retries", &self.retries)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Body::Empty => f.write_str("Body::Empty"),
+            Body::Buffer(ref buf) => write!(f, "Body::Buffer({} bytes)", buf.len()),
+            Body::Stream(_) => f.write_str("Body::Stream(..)")
+        }
+    }
+}
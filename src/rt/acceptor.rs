@@ -5,7 +5,7 @@ use mio::{EventLoop, EventSet};
 use mio::tcp::TcpListener;
 
 use rt::loophandler::{LoopHandler, IoMachine, EventMachine};
-use rt::connection::Connection;
+use rt::connection::{Connection, Remote};
 use rt::Metadata;
 
 use Handler as HttpHandler;
@@ -39,9 +39,20 @@ impl EventMachine for IoMachine<Acceptor> {
                 }
             };
 
-            handler.register(
-                Connection::new(conn.0, self.io.handler.clone(), self.io.metadata.clone()),
-                event_loop, EventSet::readable() | EventSet::hup());
+            let channel = event_loop.channel();
+            let (connection_handler, metadata) =
+                (self.io.handler.clone(), self.io.metadata.clone());
+
+            let token = handler.register_with(
+                event_loop, EventSet::readable() | EventSet::writable() | EventSet::hup(),
+                move |token| {
+                    Connection::new(conn.0, connection_handler, metadata,
+                                    Remote::new(channel, token))
+                });
+
+            if let Some(machine) = handler.connection(token) {
+                machine.schedule(event_loop);
+            }
         }
 
         Some(self)
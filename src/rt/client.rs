@@ -0,0 +1,102 @@
+use std::time::Duration;
+use std::fmt;
+
+use rt::connection::{Connection, Remote};
+
+use message::{Request, Response};
+
+use prelude::*;
+
+/// A connection to a server, over which requests are multiplexed on
+/// separate streams.
+#[derive(Clone)]
+pub struct Client {
+    remote: Remote
+}
+
+impl Client {
+    pub fn new(remote: Remote) -> Client {
+        Client { remote: remote }
+    }
+
+    /// Send a request on a new stream.
+    ///
+    /// The Future fails if the server refuses or resets the stream, and is
+    /// aborted if the connection closes before the response is received.
+    pub fn send_request(&self, request: Request) -> Future<Response, Error> {
+        let (complete, response) = Future::pair();
+
+        // If the event loop is gone, dropping `complete` aborts the response.
+        let _ = self.remote.run(move |connection: &mut Connection| {
+            connection.send_request(request, complete)
+        });
+
+        response
+    }
+
+    /// Send a PING to the server.
+    ///
+    /// The Future resolves to the round trip time once the server
+    /// acknowledges the PING, and is aborted if the connection closes first.
+    pub fn ping(&self) -> Future<Duration, Error> {
+        let (complete, rtt) = Future::pair();
+
+        let _ = self.remote.run(move |connection: &mut Connection| {
+            connection.ping(complete)
+        });
+
+        rtt
+    }
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("transfer::rt::Client")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mio::EventLoopConfig;
+
+    use rt;
+    use rt::test::{ThreadExecutor, NoopHandler, metadata, serve};
+    use message::{Request, Response};
+
+    use prelude::*;
+
+    #[test]
+    fn test_ping() {
+        let handle = rt::start(EventLoopConfig::new(), metadata(ThreadExecutor)).unwrap();
+        let address = serve(&handle, NoopHandler);
+
+        let client = handle.connect(address).unwrap().await().unwrap();
+        client.ping().await().unwrap();
+
+        handle.shutdown().unwrap().await().unwrap();
+    }
+
+    struct PingHandler;
+
+    impl Handler for PingHandler {
+        fn handle(&self, request: Request) -> Future<Response, Error> {
+            request.ping().map(|_| Response::new(200))
+        }
+    }
+
+    #[test]
+    fn test_ping_client() {
+        let handle = rt::start(EventLoopConfig::new(), metadata(ThreadExecutor)).unwrap();
+        let address = serve(&handle, PingHandler);
+
+        // The server only responds once the client acknowledged its PING.
+        let client = handle.connect(address).unwrap().await().unwrap();
+        let response = client.send_request(Request::new("GET", "/")).await().unwrap();
+        assert_eq!(response.status, 200);
+
+        // Requests which were not received from a client cannot be pinged.
+        assert!(Request::new("GET", "/").ping().await().is_err());
+
+        handle.shutdown().unwrap().await().unwrap();
+    }
+}
@@ -1,42 +1,787 @@
+use std::collections::{HashMap, VecDeque};
+use std::boxed::FnBox;
+use std::ops::Deref;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::Duration;
+use std::{cmp, fmt, mem};
 
-use mio::{EventLoop, EventSet, TryRead};
+use mio::{self, EventLoop, EventSet, Token, TryRead, TryWrite};
 use mio::tcp::TcpStream;
 
-use appendbuf::AppendBuf;
+use appendbuf::{AppendBuf, Slice};
 
 use rt::loophandler::{LoopHandler, IoMachine, EventMachine};
-use rt::Metadata;
+use rt::retry::{Replay, Retry};
+use rt::{Metadata, Keepalive, Message, POOL_IDLE_TIMEOUT_MS};
 
-use http::parser::{self, FrameHeader, Frame};
+use http::parser::{FrameHeader, Frame, ErrorCode, Kind, StreamIdentifier};
 use http::encoder::{Encoder, EncodeResult};
+use http::error::{NO_ERROR, PROTOCOL_ERROR, INTERNAL_ERROR, FRAME_SIZE_ERROR, REFUSED_STREAM,
+                  SETTINGS_TIMEOUT};
+use http::hpack::HeaderField;
+use http::{Http2, Scope, Event, Role};
 use http;
 
+use eventual::{Complete, Sender, AsyncResult};
+use message::{Request, Response, Body, Headers, Push};
+
 use prelude::*;
 use Handler as HttpHandler;
 
-const FRAME_PAYLOAD_MAX_LENGTH: usize = 1024 * 16;
 const FRAME_HEADER_LENGTH: usize = 9;
 
-#[derive(Debug)]
+/// The size of the buffers frames are read into, which fits one frame of
+/// the smallest maximum size. Larger frames get a buffer of their own size.
+const READ_BUFFER_SIZE: usize = FRAME_HEADER_LENGTH + http::settings::MIN_FRAME_SIZE as usize;
+
+/// How long the peer has to acknowledge our SETTINGS.
+const SETTINGS_TIMEOUT_MS: u64 = 10 * 1000;
+
+// Numbers connections, so that timeouts scheduled for a connection which has
+// been removed are not mistaken for those of a later connection registered
+// with the same Token, which the slab reuses.
+static GENERATION: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Timeouts scheduled for a specific Connection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Timeout {
+    /// The peer must have acknowledged our SETTINGS by now.
+    Settings,
+
+    /// Time to send the next keepalive PING.
+    Keepalive,
+
+    /// The peer must have acknowledged the keepalive PING with the given
+    /// opaque data by now.
+    KeepaliveAck(u64),
+
+    /// Time to check whether a pooled client connection is idle.
+    Idle
+}
+
+/// A callback run on a Connection, on its event loop.
+pub type Thunk = Box<for<'a> FnBox<(&'a mut Connection,), Output=()> + Send>;
+
+/// A handle for running callbacks on a Connection from other threads.
+#[derive(Clone)]
+pub struct Remote {
+    channel: mio::Sender<Message>,
+    token: Token
+}
+
+impl Remote {
+    pub fn new(channel: mio::Sender<Message>, token: Token) -> Remote {
+        Remote { channel: channel, token: token }
+    }
+
+    /// Run the callback on the Connection, if it still exists.
+    pub fn run<F>(&self, cb: F) -> Result<()>
+    where F: for<'a> FnBox<(&'a mut Connection,), Output=()> + Send + 'static {
+        Ok(try!(self.channel.send(Message::Connection(self.token, Box::new(cb)))))
+    }
+}
+
 pub struct Connection {
     pub connection: TcpStream,
     http2: http::Http2,
     current: Option<FrameHeader>,
     buffer: AppendBuf,
+
+    // The start of the bytes in `buffer` which have not yet been parsed.
+    position: usize,
+
+    // The handler requests are dispatched to, on server connections.
+    handler: Option<Arc<Box<HttpHandler>>>,
+    metadata: Metadata,
+    remote: Remote,
+
+    // Requests sent on client connections, waiting for their response.
+    responses: HashMap<StreamIdentifier, Pending>,
+
+    // The bodies of requests or responses which are still being received.
+    bodies: HashMap<StreamIdentifier, IncomingBody>,
+
+    // Has the client connection preface been received and validated?
+    // Clients receive no preface, so this is always set for them.
+    preface: bool,
+
+    // The part of the connection preface still to be written. Only
+    // clients send a preface, see Section 3.5 of the spec.
+    unsent_preface: &'static [u8],
+
+    // Once set, no more frames are read and the connection is closed
+    // as soon as all outgoing frames have been written.
+    closing: bool,
+
+    // Set when a pooled connection is found without streams, and cleared
+    // by new requests. A connection still idle at the next check is closed.
+    idle: bool,
+
+    // Is this a client connection shared by Pools, which is closed once
+    // idle? Other connections are kept open while the peer keeps them.
+    pooled: bool,
+
+    keepalive: Option<Keepalive>,
+
+    // Distinguishes this connection from others registered with its Token.
+    generation: usize
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("connection", &self.connection)
+            .field("http2", &self.http2)
+            .field("current", &self.current)
+            .field("responses", &self.responses.len())
+            .field("bodies", &self.bodies.len())
+            .field("preface", &self.preface)
+            .field("closing", &self.closing)
+            .field("generation", &self.generation)
+            .finish()
+    }
+}
+
+// A request sent on a client connection, waiting for its response.
+struct Pending {
+    complete: Complete<Response, Error>,
+
+    // A copy of a pooled request, kept while it is small enough so that the
+    // request can be retried if the server does not process it.
+    replay: Option<Replay>,
+
+    // How many times the request has been retried.
+    retries: u32
+}
+
+// The sending half of a request or response body.
+//
+// A Sender can only send one chunk at a time, so chunks received while it
+// is busy are queued. The flow control space used by a chunk is released
+// once it has been received, so a slow consumer slows the peer.
+struct IncomingBody {
+    sender: Option<Sender<Slice, Error>>,
+    queue: VecDeque<Slice>,
+
+    // The length of the chunk currently being sent to the handler.
+    sending: usize,
+
+    // Completed with the trailers, or an empty list, once the body ends.
+    trailers: Option<Complete<Headers, Error>>,
+
+    // Has END_STREAM been received?
+    end: bool
 }
 
 impl Connection {
     pub fn new(connection: TcpStream,
                handler: Arc<Box<HttpHandler>>,
-               metadata: Metadata) -> Connection {
+               metadata: Metadata,
+               remote: Remote) -> Connection {
+        let settings = metadata.settings;
+        Connection::open(connection, Http2::new(), settings, Some(handler), metadata, remote)
+    }
+
+    /// A connection we opened to a server, on which requests are sent with
+    /// `send_request`.
+    pub fn client(connection: TcpStream, metadata: Metadata, remote: Remote) -> Connection {
+        // Pushed responses are not handled, so the server may not push.
+        let mut settings = metadata.settings;
+        settings.enable_push = false;
+
+        let mut this = Connection::open(connection, Http2::client(), settings, None,
+                                        metadata, remote);
+        this.preface = true;
+        this.unsent_preface = http::PREFACE;
+        this
+    }
+
+    fn open(connection: TcpStream, mut http2: Http2, settings: http::Settings,
+            handler: Option<Arc<Box<HttpHandler>>>, metadata: Metadata,
+            remote: Remote) -> Connection {
+        http2.padding = metadata.padding;
+        http2.send_settings(settings);
         Connection {
             connection: connection,
-            http2: http::Http2::new(),
+            http2: http2,
             current: None,
-            buffer: buf(),
+            buffer: AppendBuf::new(READ_BUFFER_SIZE),
+            position: 0,
+            handler: handler,
+            keepalive: metadata.keepalive,
+            metadata: metadata,
+            remote: remote,
+            responses: HashMap::new(),
+            bodies: HashMap::new(),
+            preface: false,
+            unsent_preface: &[],
+            closing: false,
+            idle: false,
+            pooled: false,
+            generation: GENERATION.fetch_add(1, Ordering::SeqCst)
+        }
+    }
+
+    // Move the bytes not yet parsed into a new buffer, with room for all of
+    // the frame being read. Frames parsed from the old buffer may still
+    // refer to it, so it is not reused.
+    fn make_room(&mut self) {
+        let needed = match self.current {
+            Some(header) => FRAME_HEADER_LENGTH + header.length as usize,
+            None => FRAME_HEADER_LENGTH
+        };
+
+        debug!("Moving {} unparsed bytes to a new buffer.", self.buffer.len() - self.position);
+        let mut buffer = AppendBuf::new(cmp::max(needed, READ_BUFFER_SIZE));
+        buffer.fill(&self.buffer[self.position..]);
+
+        self.buffer = buffer;
+        self.position = 0;
+    }
+
+    /// Send a GOAWAY with the given error and close the connection once
+    /// all outgoing frames have been written.
+    fn close(&mut self, error: ErrorCode) {
+        debug!("Closing connection with error {:?}", error);
+        self.http2.go_away(error);
+        self.closing = true;
+    }
+
+    /// Respond to a protocol error.
+    ///
+    /// Stream errors reset the stream, connection errors close the connection.
+    fn error(&mut self, error: http::Error) {
+        match error.scope {
+            Scope::Stream(id) => {
+                debug!("{}, resetting stream.", error);
+
+                if let Err(e) = self.http2.reset(id, error.code) {
+                    debug!("Could not reset stream {:?}: {}", id, e);
+                }
+
+                if let Some(pending) = self.responses.remove(&id) {
+                    pending.complete.fail(Error::Http(error));
+                }
+            },
+            Scope::Connection => {
+                error!("{}, closing connection.", error);
+                self.close(error.code);
+            }
+        }
+    }
+
+    /// Act on the headers, data and resets received from the peer.
+    fn dispatch(&mut self) {
+        while let Some(event) = self.http2.events.pop_front() {
+            match event {
+                Event::Headers { id, headers, end_stream } => match self.http2.role {
+                    Role::Server => self.request(id, headers, end_stream),
+                    Role::Client => self.response(id, headers, end_stream)
+                },
+                Event::Trailers { id, headers } => self.trailers(id, headers),
+                Event::Data { id, data, end_stream } =>
+                    self.body(id, Some(data), end_stream),
+                Event::Reset { id, error } => {
+                    let reset = || {
+                        Error::Http(http::Error::stream(id, error, "stream reset by peer"))
+                    };
+
+                    // Refused streams were not processed, see Section 8.1.4 of the spec.
+                    if error == REFUSED_STREAM {
+                        self.refused(id, http::Error::stream(id, error, "stream refused"));
+                    } else if let Some(pending) = self.responses.remove(&id) {
+                        pending.complete.fail(reset());
+                    }
+
+                    if let Some(mut body) = self.bodies.remove(&id) {
+                        if let Some(sender) = body.sender.take() { sender.fail(reset()) }
+                        if let Some(trailers) = body.trailers.take() { trailers.fail(reset()) }
+
+                        self.discard(id, body);
+                    }
+                },
+                Event::GoAway { last, .. } => {
+                    let unprocessed = self.responses.keys()
+                        .filter(|id| id.0 > last.0).cloned().collect::<Vec<_>>();
+
+                    for id in unprocessed {
+                        let error = http::Error::stream(id, REFUSED_STREAM,
+                                                        "stream not processed before GOAWAY");
+                        self.refused(id, error);
+                    }
+                }
+            }
+        }
+    }
+
+    // Retry a request the server did not process on another connection,
+    // failing it with `error` if it cannot be retried.
+    fn refused(&mut self, id: StreamIdentifier, error: http::Error) {
+        let Pending { complete, replay, retries } = match self.responses.remove(&id) {
+            Some(pending) => pending,
+            None => return
+        };
+
+        match replay {
+            Some(replay) if replay.is_complete() => {
+                debug!("Request on stream {:?} was not processed, retrying.", id);
+                let retry = Retry {
+                    replay: replay,
+                    complete: complete,
+                    retries: retries + 1,
+                    refused_by: self.remote.token,
+                    error: error
+                };
+
+                // If the event loop is gone, dropping the retry aborts the response.
+                let _ = self.remote.channel.send(Message::Retry(retry));
+            },
+            _ => complete.fail(Error::Http(error))
+        }
+    }
+
+    // The body and trailers of a request or response being received on a
+    // stream, which are fed by later DATA and trailers.
+    fn incoming(&mut self, id: StreamIdentifier)
+                -> (Stream<Slice, Error>, Future<Headers, Error>) {
+        let (sender, body) = Stream::pair();
+        let (complete, trailers) = Future::pair();
+        self.bodies.insert(id, IncomingBody {
+            sender: Some(sender),
+            queue: VecDeque::new(),
+            sending: 0,
+            trailers: Some(complete),
+            end: false
+        });
+
+        (body, trailers)
+    }
+
+    // Start handling a new request.
+    fn request(&mut self, id: StreamIdentifier, headers: Vec<HeaderField>, end_stream: bool) {
+        let handler = match self.handler {
+            Some(ref handler) => handler.clone(),
+            None => return error!("Received request on stream {:?} without a handler.", id)
+        };
+
+        let (body, trailers) = if end_stream {
+            (Stream::empty(), Future::of(Vec::new()))
+        } else {
+            self.incoming(id)
+        };
+
+        let push = Push::new(self.remote.clone(), id);
+        let request = match Request::from_fields(headers, body, trailers, push) {
+            Ok(request) => request,
+            Err(message) => {
+                debug!("Malformed request on stream {:?}: {}", id, message);
+                self.bodies.remove(&id);
+                if let Err(e) = self.http2.reset(id, PROTOCOL_ERROR) {
+                    debug!("Could not reset stream {:?}: {}", id, e);
+                }
+
+                return
+            }
+        };
+
+        debug!("Dispatching request {:?} on stream {:?}", request, id);
+
+        let remote = self.remote.clone();
+        self.metadata.executor.execute(Box::new(move || {
+            handler.handle(request).receive(move |result| {
+                let _ = remote.run(move |connection: &mut Connection| {
+                    connection.respond(id, result)
+                });
+            });
+        }));
+    }
+
+    // Complete a request we sent with the response received for it.
+    fn response(&mut self, id: StreamIdentifier, headers: Vec<HeaderField>, end_stream: bool) {
+        let Pending { complete, retries, .. } = match self.responses.remove(&id) {
+            Some(pending) => pending,
+            None => return debug!("Ignoring response on stream {:?} without a request.", id)
+        };
+
+        let (body, trailers) = if end_stream {
+            (Body::Empty, None)
+        } else {
+            let (body, trailers) = self.incoming(id);
+            (Body::Stream(body.map(|chunk: Slice| chunk.to_vec())), Some(trailers))
+        };
+
+        match Response::from_fields(headers, body, trailers) {
+            Ok(mut response) => {
+                debug!("Received response {:?} on stream {:?}", response, id);
+                response.retries = retries;
+                complete.complete(response)
+            },
+            Err(message) => {
+                debug!("Malformed response on stream {:?}: {}", id, message);
+                self.bodies.remove(&id);
+                if let Err(e) = self.http2.reset(id, PROTOCOL_ERROR) {
+                    debug!("Could not reset stream {:?}: {}", id, e);
+                }
+
+                complete.fail(Error::Http(http::Error::stream(id, PROTOCOL_ERROR, message)))
+            }
+        }
+    }
+
+    // Finish the body of a request or response with trailers.
+    fn trailers(&mut self, id: StreamIdentifier, headers: Vec<HeaderField>) {
+        if let Some(trailers) = self.bodies.get_mut(&id).and_then(|body| body.trailers.take()) {
+            trailers.complete(headers);
+        }
+
+        self.body(id, None, true)
+    }
+
+    // Queue a chunk of a request or response body to be received.
+    fn body(&mut self, id: StreamIdentifier, chunk: Option<Slice>, end_stream: bool) {
+        let discarded = match self.bodies.get_mut(&id) {
+            Some(body) => {
+                body.queue.extend(chunk);
+                body.end = end_stream;
+
+                if end_stream {
+                    if let Some(trailers) = body.trailers.take() {
+                        trailers.complete(Vec::new());
+                    }
+                }
+
+                None
+            },
+            None => chunk
+        };
+
+        if let Some(chunk) = discarded {
+            debug!("Discarding body on stream {:?}", id);
+            return self.release(id, chunk.len())
+        }
+
+        self.pump(id)
+    }
+
+    // Send the next chunk of a body, unless the sender is busy.
+    fn pump(&mut self, id: StreamIdentifier) {
+        let mut body = match self.bodies.remove(&id) {
+            Some(body) => body,
+            None => return
+        };
+
+        let sender = match body.sender.take() {
+            Some(sender) => sender,
+            None => {
+                self.bodies.insert(id, body);
+                return
+            }
+        };
+
+        match body.queue.pop_front() {
+            Some(chunk) => {
+                let remote = self.remote.clone();
+                body.sending = chunk.len();
+                sender.send(chunk).receive(move |result| {
+                    let _ = remote.run(move |connection: &mut Connection| {
+                        connection.body_ready(id, result.ok())
+                    });
+                });
+
+                self.bodies.insert(id, body);
+            },
+
+            // Dropping the sender ends the body.
+            None if body.end => debug!("Finished request body on stream {:?}", id),
+            None => {
+                body.sender = Some(sender);
+                self.bodies.insert(id, body);
+            }
+        }
+    }
+
+    // Called once a chunk of a body has been received.
+    fn body_ready(&mut self, id: StreamIdentifier, sender: Option<Sender<Slice, Error>>) {
+        let received = match (self.bodies.get_mut(&id), sender) {
+            (Some(body), Some(sender)) => {
+                body.sender = Some(sender);
+                Some(mem::replace(&mut body.sending, 0))
+            },
+            _ => None
+        };
+
+        let received = match received {
+            Some(received) => received,
+            None => {
+                debug!("Body on stream {:?} was dropped.", id);
+                if let Some(body) = self.bodies.remove(&id) {
+                    self.discard(id, body);
+                }
+
+                return
+            }
+        };
+
+        self.release(id, received);
+        self.pump(id)
+    }
+
+    // Release the space used by a body which will never be received.
+    fn discard(&mut self, id: StreamIdentifier, body: IncomingBody) {
+        let queued = body.queue.iter().fold(0, |total, chunk| total + chunk.len());
+        self.release(id, body.sending + queued)
+    }
+
+    // Let the peer send more DATA, now that `length` bytes were consumed.
+    fn release(&mut self, id: StreamIdentifier, length: usize) {
+        if let Err(e) = self.http2.release(id, length) {
+            self.error(e);
+        }
+    }
+
+    /// Send the response produced by the handler for the request on a stream.
+    pub fn respond(&mut self, id: StreamIdentifier, result: AsyncResult<Response, Error>) {
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Handler failed for stream {:?}: {:?}", id, e);
+                if let Err(e) = self.http2.reset(id, INTERNAL_ERROR) {
+                    debug!("Could not reset stream {:?}: {}", id, e);
+                }
+
+                return
+            }
+        };
+
+        debug!("Sending response {:?} on stream {:?}", response, id);
+        let fields = response.fields();
+        let Response { body, trailers, .. } = response;
+
+        let result = match (body, trailers) {
+            (Body::Empty, None) => self.http2.send_headers(id, &fields, true),
+            (Body::Buffer(buf), None) => self.http2.send_headers(id, &fields, false)
+                .and_then(|()| self.http2.send_data(id, &buf, true)),
+            (Body::Empty, Some(trailers)) => self.http2.send_headers(id, &fields, false)
+                .map(|()| self.end(id, Some(trailers))),
+            (Body::Buffer(buf), Some(trailers)) => self.http2.send_headers(id, &fields, false)
+                .and_then(|()| self.http2.send_data(id, &buf, false))
+                .map(|()| self.end(id, Some(trailers))),
+            (Body::Stream(stream), trailers) => self.http2.send_headers(id, &fields, false)
+                .map(|()| self.pull(id, stream, trailers))
+        };
+
+        if let Err(e) = result {
+            debug!("Could not send response on stream {:?}: {}", id, e);
         }
     }
+
+    /// Promise a pushed response on a request's stream, and send the
+    /// response once it is ready.
+    pub fn push(&mut self, parent: StreamIdentifier, fields: Vec<HeaderField>,
+                response: Future<Response, Error>) {
+        let promised = match self.http2.push_promise(parent, &fields) {
+            Ok(promised) => promised,
+            Err(e) => return debug!("Not pushing on stream {:?}: {}", parent, e)
+        };
+
+        debug!("Promised push on stream {:?} for stream {:?}", promised, parent);
+
+        let remote = self.remote.clone();
+        response.receive(move |result| {
+            let _ = remote.run(move |connection: &mut Connection| {
+                connection.respond(promised, result)
+            });
+        });
+    }
+
+    /// Send a request on a new stream, completing `complete` with its
+    /// response once it is received.
+    pub fn send_request(&mut self, request: Request, complete: Complete<Response, Error>) {
+        self.send(request, complete, None)
+    }
+
+    /// Send a request for a Pool, which retries the request on another
+    /// connection if the server does not process it.
+    pub fn send_pooled(&mut self, request: Request, complete: Complete<Response, Error>) {
+        let replay = Replay::new(&request);
+        self.send(request, complete, Some(replay))
+    }
+
+    fn send(&mut self, request: Request, complete: Complete<Response, Error>,
+            replay: Option<Replay>) {
+        self.idle = false;
+
+        let fields = request.fields();
+        let Request { body, trailers, .. } = request;
+
+        match self.http2.send_request(&fields, false) {
+            Ok(id) => {
+                debug!("Sending request {:?} on stream {:?}", fields, id);
+                self.responses.insert(id, Pending {
+                    complete: complete,
+                    replay: replay,
+                    retries: 0
+                });
+
+                self.pull(id, body, Some(trailers))
+            },
+            Err(e) => {
+                debug!("Could not send request: {}", e);
+                complete.fail(Error::Http(e))
+            }
+        }
+    }
+
+    /// Send a request refused by another connection again.
+    ///
+    /// If this connection cannot take another stream, the request is
+    /// handed back to be sent on another connection.
+    pub fn resend(&mut self, mut retry: Retry) {
+        if !self.can_send_request() {
+            debug!("Cannot retry request on {:?}, requeueing it.", self.remote.token);
+            retry.refused_by = self.remote.token;
+
+            // If the event loop is gone, dropping the retry aborts the response.
+            let _ = self.remote.channel.send(Message::Requeue(retry));
+            return
+        }
+
+        self.idle = false;
+
+        let Retry { replay, complete, retries, .. } = retry;
+        let trailers = replay.trailers().to_vec();
+
+        let result = self.http2.send_request(replay.fields(), false).and_then(|id| {
+            debug!("Retrying request {:?} on stream {:?}", replay.fields(), id);
+
+            let end_stream = trailers.is_empty();
+            try!(self.http2.send_data(id, replay.body(), end_stream));
+            if !end_stream { try!(self.http2.send_headers(id, &trailers, true)) }
+
+            Ok(id)
+        });
+
+        match result {
+            Ok(id) => {
+                self.responses.insert(id, Pending {
+                    complete: complete,
+                    replay: Some(replay),
+                    retries: retries
+                });
+            },
+            Err(e) => {
+                debug!("Could not retry request: {}", e);
+                complete.fail(Error::Http(e))
+            }
+        }
+    }
+
+    /// Send a PING, completing `complete` with the round trip time once the
+    /// peer acknowledges it.
+    pub fn ping(&mut self, complete: Complete<Duration, Error>) {
+        self.http2.ping(move |rtt| complete.complete(rtt));
+    }
+
+    /// Distinguishes this connection from others which were registered
+    /// with the same Token before it.
+    pub fn generation(&self) -> usize { self.generation }
+
+    /// Could a request be sent on a new stream of this connection?
+    pub fn can_send_request(&self) -> bool {
+        !self.closing && self.http2.can_send_request()
+    }
+
+    // Wait for the next chunk of a streamed body.
+    fn pull<T>(&self, id: StreamIdentifier, stream: Stream<T, Error>,
+               trailers: Option<Future<Headers, Error>>)
+    where T: Deref<Target=[u8]> + Send + 'static {
+        let remote = self.remote.clone();
+        stream.receive(move |head| {
+            let _ = remote.run(move |connection: &mut Connection| {
+                connection.chunk(id, head, trailers)
+            });
+        });
+    }
+
+    // Send a chunk of a streamed body, pulling the next chunk once it has
+    // been written.
+    fn chunk<T>(&mut self, id: StreamIdentifier,
+                head: AsyncResult<Option<(T, Stream<T, Error>)>, Error>,
+                trailers: Option<Future<Headers, Error>>)
+    where T: Deref<Target=[u8]> + Send + 'static {
+        let result = match head {
+            Ok(Some((chunk, rest))) => {
+                self.keep(id, |replay| replay.push(&chunk));
+
+                let remote = self.remote.clone();
+                self.http2.send_data_with(id, &chunk, false, move |_: &mut Http2| {
+                    let _ = remote.run(move |connection: &mut Connection| {
+                        connection.pull(id, rest, trailers)
+                    });
+                })
+            },
+            Ok(None) => return self.end(id, trailers),
+            Err(e) => {
+                error!("Body failed on stream {:?}: {:?}", id, e);
+                return self.error(http::Error::stream(id, INTERNAL_ERROR, "body failed"))
+            }
+        };
+
+        if let Err(e) = result {
+            debug!("Could not send body on stream {:?}: {}", id, e);
+        }
+    }
+
+    // End a stream whose body has been sent, with trailers if it has any.
+    fn end(&mut self, id: StreamIdentifier, trailers: Option<Future<Headers, Error>>) {
+        let trailers = match trailers {
+            Some(trailers) => trailers,
+            None => {
+                if let Err(e) = self.http2.send_data(id, &[], true) {
+                    debug!("Could not end stream {:?}: {}", id, e);
+                }
+
+                return
+            }
+        };
+
+        let remote = self.remote.clone();
+        trailers.receive(move |result| {
+            let _ = remote.run(move |connection: &mut Connection| {
+                if let Ok(ref trailers) = result {
+                    let trailers = trailers.clone();
+                    connection.keep(id, move |replay| { replay.end(trailers); true });
+                }
+
+                let result = match result {
+                    Ok(ref trailers) if trailers.is_empty() =>
+                        connection.http2.send_data(id, &[], true),
+                    Ok(trailers) => connection.http2.send_headers(id, &trailers, true),
+                    Err(e) => {
+                        error!("Trailers failed on stream {:?}: {:?}", id, e);
+                        let error = http::Error::stream(id, INTERNAL_ERROR, "trailers failed");
+                        return connection.error(error)
+                    }
+                };
+
+                if let Err(e) = result {
+                    debug!("Could not send trailers on stream {:?}: {}", id, e);
+                }
+            });
+        });
+    }
+
+    // Update the copy kept of a pooled request as it is sent, forgetting
+    // the copy if `update` returns false.
+    fn keep<F>(&mut self, id: StreamIdentifier, update: F)
+    where F: FnOnce(&mut Replay) -> bool {
+        if let Some(pending) = self.responses.get_mut(&id) {
+            let kept = pending.replay.as_mut().map_or(false, update);
+            if !kept { pending.replay = None }
+        }
+    }
+
 }
 
 impl EventMachine for IoMachine<Connection> {
@@ -60,75 +805,245 @@ impl EventMachine for IoMachine<Connection> {
         }
 
         // If there have not been any fatal errors, and the connection can procede.
-        optself.map(|mut this| {
-            // and if the connection is not currently waiting for writable events
-            // and there is data to write, express interest in future writable
-            // events.
-            if !this.interest.contains(EventSet::writable()) &&
-               !this.io.http2.outgoing.is_empty() {
-                this.interest.insert(EventSet::writable());
-                handler.deregister(&mut this, event_loop, EventSet::none());
-            }
-
-            this
-        })
+        optself.map(|this| this.flush(event_loop, handler))
+            .and_then(|this| this.finish(event_loop, handler))
     }
 }
 
 impl IoMachine<Connection> {
+    /// Schedule the timeouts needed by a newly registered Connection.
+    pub fn schedule(&self, event_loop: &mut EventLoop<LoopHandler>) {
+        self.schedule_timeout(Timeout::Settings, SETTINGS_TIMEOUT_MS, event_loop);
+
+        if let Some(keepalive) = self.io.keepalive {
+            self.schedule_timeout(Timeout::Keepalive, keepalive.interval_ms, event_loop);
+        }
+    }
+
+    /// Mark this as a pooled client connection, and schedule its idle
+    /// checks.
+    pub fn schedule_idle(&mut self, event_loop: &mut EventLoop<LoopHandler>) {
+        self.io.pooled = true;
+        self.schedule_timeout(Timeout::Idle, POOL_IDLE_TIMEOUT_MS, event_loop);
+    }
+
+    fn schedule_timeout(&self, timeout: Timeout, ms: u64,
+                        event_loop: &mut EventLoop<LoopHandler>) {
+        let scheduled = ::rt::Timeout::Connection(self.token, self.io.generation, timeout);
+        if let Err(e) = event_loop.timeout_ms(scheduled, ms) {
+            error!("Failed to schedule timeout {:?}: {:?}", timeout, e);
+        }
+    }
+
+    pub fn timeout(mut self, event_loop: &mut EventLoop<LoopHandler>,
+                   handler: &mut LoopHandler, generation: usize,
+                   timeout: Timeout) -> Option<Self> {
+        if generation != self.io.generation {
+            debug!("Ignoring timeout {:?} of a removed connection on {:?}.",
+                   timeout, self.token);
+            return Some(self)
+        }
+
+        debug!("Timeout {:?} fired on connection {:?}.", timeout, self.token);
+
+        match timeout {
+            Timeout::Settings => {
+                if self.io.http2.settings_pending() {
+                    error!("Peer did not acknowledge SETTINGS in time.");
+                    self.io.close(SETTINGS_TIMEOUT);
+                }
+            },
+            Timeout::Keepalive => {
+                if let (Some(keepalive), false) = (self.io.keepalive, self.io.closing) {
+                    let data = self.io.http2.ping(|rtt| {
+                        debug!("Keepalive PING acknowledged after {:?}", rtt);
+                    });
+
+                    self.schedule_timeout(Timeout::KeepaliveAck(data),
+                                          keepalive.timeout_ms, event_loop);
+                    self.schedule_timeout(Timeout::Keepalive,
+                                          keepalive.interval_ms, event_loop);
+                }
+            },
+            Timeout::Idle if !self.io.pooled => {
+                debug!("Ignoring idle check of connection {:?}, which is not pooled.",
+                       self.token);
+            },
+            Timeout::Idle => {
+                let idle = self.io.http2.active_streams() == 0;
+
+                if idle && self.io.idle {
+                    debug!("Closing idle pooled connection {:?}.", self.token);
+                    self.io.http2.go_away(NO_ERROR);
+                } else if !self.io.closing {
+                    self.io.idle = idle;
+                    self.schedule_timeout(Timeout::Idle, POOL_IDLE_TIMEOUT_MS, event_loop);
+                }
+            },
+            Timeout::KeepaliveAck(data) => {
+                // The peer is unresponsive, so there is no point in
+                // trying to send it a GOAWAY.
+                if self.io.http2.ping_pending(data) {
+                    error!("Peer did not acknowledge keepalive PING, closing connection.");
+                    handler.deregister(&mut self, event_loop, EventSet::all());
+                    return None
+                }
+            }
+        }
+
+        Some(self.flush(event_loop, handler))
+    }
+
+    /// Stop accepting new streams, and close the connection once all
+    /// in-flight streams are finished.
+    pub fn drain(mut self, event_loop: &mut EventLoop<LoopHandler>,
+                 handler: &mut LoopHandler) -> Option<Self> {
+        if !self.io.closing {
+            debug!("Draining connection {:?}", self.token);
+            self.io.http2.go_away(NO_ERROR);
+        }
+
+        self.flush(event_loop, handler).finish(event_loop, handler)
+    }
+
+    /// Run a callback sent from another thread.
+    pub fn run(mut self, event_loop: &mut EventLoop<LoopHandler>,
+               handler: &mut LoopHandler, thunk: Thunk) -> Option<Self> {
+        thunk.call_box((&mut self.io,));
+        self.flush(event_loop, handler).finish(event_loop, handler)
+    }
+
+    fn flush(mut self, event_loop: &mut EventLoop<LoopHandler>,
+             handler: &mut LoopHandler) -> Self {
+        // If the connection is not currently waiting for writable events
+        // and there is data to write, express interest in future writable
+        // events.
+        if !self.interest.contains(EventSet::writable()) &&
+           !self.io.http2.outgoing.is_empty() {
+            self.interest.insert(EventSet::writable());
+            handler.deregister(&mut self, event_loop, EventSet::none());
+        }
+
+        self
+    }
+
+    // Drop a connection which has been drained and has nothing left to write.
+    fn finish(mut self, event_loop: &mut EventLoop<LoopHandler>,
+              handler: &mut LoopHandler) -> Option<Self> {
+        if self.io.http2.drained() && self.io.http2.outgoing.is_empty() {
+            debug!("Connection {:?} drained, closing.", self.token);
+            handler.deregister(&mut self, event_loop, EventSet::all());
+            None
+        } else {
+            Some(self)
+        }
+    }
+
+    fn parse_preface(&mut self) -> bool {
+        let preface = http::PREFACE;
+        let len = ::std::cmp::min(self.io.buffer.len(), preface.len());
+
+        if &self.io.buffer[..len] != &preface[..len] {
+            debug!("Invalid connection preface: {:?}", &self.io.buffer[..len]);
+            self.io.close(PROTOCOL_ERROR);
+            return false
+        }
+
+        if len == preface.len() {
+            debug!("Received valid connection preface.");
+            self.io.preface = true;
+            self.io.position = preface.len();
+        }
+
+        self.io.preface
+    }
+
     fn parse_frames(mut self) -> Option<Self> {
+        if self.io.closing {
+            debug!("Connection is closing, discarding {} bytes.",
+                   self.io.buffer.len() - self.io.position);
+            self.io.position = self.io.buffer.len();
+            return Some(self)
+        }
+
+        if !self.io.preface && !self.parse_preface() {
+            return Some(self)
+        }
+
         // Parse as many frames as we can.
         loop {
             if let Some(current) = self.io.current {
-                debug!("FrameHeader already parsed, trying to parse frame.");
-                let frame = Frame::parse(current,
-                                         self.io.buffer.slice()
-                                             .slice_from(FRAME_HEADER_LENGTH));
+                let start = self.io.position + FRAME_HEADER_LENGTH;
+                let end = start + current.length as usize;
+
+                if self.io.buffer.len() < end {
+                    debug!("Not a full frame was read, have {:?} of {:?} bytes",
+                           self.io.buffer.len() - start, current.length);
+                    return Some(self)
+                }
+
+                let frame = Frame::parse(current, self.io.buffer.slice().slice(start, end));
+
+                // The type of extension frames is only known from the raw header.
+                let kind = self.io.buffer[self.io.position + 3];
+                let frame = frame.map(|frame| match current.kind {
+                    Kind::Unregistered => frame.extension(kind),
+                    _ => frame
+                });
 
                 match frame {
-                    Err(parser::Error::Incomplete) => {
-                        debug!("Not a full frame was parsed, tried to parse {:?} bytes",
-                               self.io.buffer.len());
-                        return Some(self)
-                    },
                     Err(e) => {
-                        debug!("Error parsing frame: {:?}", e);
-                        return None;
+                        error!("Error parsing frame: {:?}", e);
+                        let error = e.to_http(Some(&current));
+                        let stream = error.stream_id().is_some();
+                        self.io.error(error);
+
+                        // A stream error only resets the stream, so the
+                        // connection moves on to the next frame.
+                        if !stream { return Some(self) }
+
+                        self.io.current = None;
+                        self.io.position = end;
                     },
                     Ok(frame) => {
                         debug!("Succesfully parsed frame {:?}", frame);
 
-                        // Send the frame.
+                        // Apply the frame, stopping if it caused a connection error.
                         if let Err(e) = self.io.http2.apply(frame) {
-                            error!("Http2 error: {:?}", e);
-                            return None
+                            self.io.error(e);
+                            if self.io.closing { return Some(self) }
                         }
 
-                        // Recycle self.io.current and self.io.buffer.
-                        self.io.current = None;
-
-                        debug!("Creating a new buffer to replace with old.");
-                        let mut newbuffer = buf();
-                        newbuffer.fill(
-                            &self.io.buffer[FRAME_HEADER_LENGTH + current.length as usize..]);
+                        self.io.dispatch();
 
-                        trace!("newbuffer contents = {:?}", newbuffer.slice());
-                        self.io.buffer = newbuffer;
+                        // Move on to the next frame in the buffer.
+                        self.io.current = None;
+                        self.io.position = end;
                     }
                 }
             } else {
                 debug!("No frame header parsed yet.");
-                let header = FrameHeader::parse(&self.io.buffer);
+                let header = FrameHeader::parse(&self.io.buffer[self.io.position..]);
 
                 match header {
                     Err(::http2parse::Error::Short) => {
                         debug!("Not enough bytes for FrameHeader: {:?} bytes",
-                               self.io.buffer.len());
+                               self.io.buffer.len() - self.io.position);
                         return Some(self)
                     },
                     Err(e) => {
-                        debug!("Error parsing frame header {:?}", e);
-                        return None
+                        error!("Error parsing frame header {:?}", e);
+                        self.io.error(http::parser::Error::from(e).to_http(None));
+                        return Some(self)
+                    },
+                    // The peer must not send frames larger than our
+                    // SETTINGS_MAX_FRAME_SIZE, see Section 4.2 of the spec.
+                    Ok(header) if header.length > self.io.http2.local.max_frame_size => {
+                        error!("Frame of {} bytes exceeds SETTINGS_MAX_FRAME_SIZE",
+                               header.length);
+                        self.io.error(http::Error::connection(FRAME_SIZE_ERROR,
+                                                              "frame too large"));
+                        return Some(self)
                     },
                     Ok(header) => {
                         debug!("Parsed header: {:?}.", header);
@@ -145,6 +1060,18 @@ impl IoMachine<Connection> {
 
         // Read in as much data as we can.
         loop {
+            // Make room by parsing the frames already read, then moving the
+            // rest into a new buffer large enough for the next frame.
+            if self.io.buffer.get_write_buf().is_empty() {
+                debug!("Read buffer is full, parsing before reading more.");
+                self = match self.parse_frames() {
+                    Some(this) => this,
+                    None => return None
+                };
+
+                self.io.make_room();
+            }
+
             debug!("Reading from connection");
             match self.io.connection.try_read(self.io.buffer.get_write_buf()) {
                 Ok(Some(0)) => {
@@ -174,6 +1101,26 @@ impl IoMachine<Connection> {
                 handler: &mut LoopHandler) -> Option<Self> {
         debug!("Connection responding to writable event.");
 
+        // Clients write the connection preface before any frames.
+        while !self.io.unsent_preface.is_empty() {
+            let unsent = self.io.unsent_preface;
+            match self.io.connection.try_write(unsent) {
+                Ok(Some(n)) => {
+                    debug!("Wrote {} bytes of the connection preface.", n);
+                    self.io.unsent_preface = &unsent[n..];
+                },
+                Ok(None) => {
+                    debug!("Write would block, yielding.");
+                    return Some(self)
+                },
+                Err(e) => {
+                    error!("Connection write error {:?}", e);
+                    handler.deregister(&mut self, event_loop, EventSet::writable());
+                    return None
+                }
+            }
+        }
+
         'writable: loop {
             if let Some((mut encoder, cb)) = self.io.http2.outgoing.current.take() {
                 debug!("Popped frame encoder from outgoing.");
@@ -211,11 +1158,15 @@ impl IoMachine<Connection> {
                     }
                 }
             } else {
-                match self.io.http2.outgoing.dequeue() {
+                match self.io.http2.dequeue() {
                     Some(e) => {
                         debug!("Pulling next encoder.");
                         self.io.http2.outgoing.current = Some(e);
                     }
+                    None if self.io.closing || self.io.http2.drained() => {
+                        debug!("Finished writing to closing connection, dropping it.");
+                        return None
+                    },
                     None => {
                         debug!("No encoders available, deregistering writable.");
                         handler.deregister(&mut self, event_loop, EventSet::writable());
@@ -226,8 +1177,3 @@ impl IoMachine<Connection> {
         }
     }
 }
-
-fn buf() -> AppendBuf {
-    AppendBuf::new(FRAME_HEADER_LENGTH + FRAME_PAYLOAD_MAX_LENGTH)
-}
-
@@ -1,35 +1,70 @@
-use super::Thunk;
+use super::Timeout;
 
 use mio::util::Slab;
+use mio::tcp::TcpStream;
 use mio::{self, EventLoop, Token, EventSet, Evented, PollOpt};
 
-use rt::connection::Connection;
+use rt::connection::{self, Connection, Remote};
 use rt::acceptor::Acceptor;
-use rt::{Message, Metadata};
+use rt::pool::{self, Connections};
+use rt::retry::{Retry, Budget, MAX_RETRIES};
+use rt::{Message, Metadata, Client};
 
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::os::unix::io::AsRawFd;
 use std::{io, mem, fmt};
 
+use eventual::Complete;
+use message::{Request, Response};
+use {Error, Result};
+
 #[derive(Debug)]
 pub struct LoopHandler {
     pub metadata: Metadata,
     pub slab: Slab<LoopMachine>,
+
+    // The client connections used by Pools.
+    pool: Connections,
+
+    // Pooled requests waiting for the address of an authority to be
+    // resolved, by authority.
+    resolving: HashMap<String, Vec<Waiting>>,
+
+    // Limits how many pooled requests are retried.
+    budget: Budget,
+
+    // Set once shutdown begins, the event loop stops once no connections
+    // remain.
+    draining: bool
 }
 
 impl LoopHandler {
     pub fn new(metadata: Metadata) -> LoopHandler {
         LoopHandler {
             metadata: metadata,
-            slab: Slab::new(32 * 1024)
+            slab: Slab::new(32 * 1024),
+            pool: Connections::new(),
+            resolving: HashMap::new(),
+            budget: Budget::new(),
+            draining: false
         }
     }
 
     pub fn register<E: Evented>(&mut self, io: E, event_loop: &mut EventLoop<Self>,
                                 interest: EventSet) -> Token
     where IoMachine<E>: Into<LoopMachine>, E: fmt::Debug {
+        self.register_with(event_loop, interest, move |_| io)
+    }
+
+    /// Register the io object created by `constructor`, which is passed the
+    /// Token the object will be registered with.
+    pub fn register_with<E: Evented, F>(&mut self, event_loop: &mut EventLoop<Self>,
+                                        interest: EventSet, constructor: F) -> Token
+    where IoMachine<E>: Into<LoopMachine>, E: fmt::Debug, F: FnOnce(Token) -> E {
         self.slab.insert_with(move |token| {
             let machine = IoMachine {
-                io: io,
+                io: constructor(token),
                 token: token,
                 interest: interest,
                 pollopt: PollOpt::edge()
@@ -42,6 +77,22 @@ impl LoopHandler {
         }).unwrap()
     }
 
+    /// The Connection registered with the given Token.
+    pub fn connection(&self, token: Token) -> Option<&IoMachine<Connection>> {
+        match self.slab.get(token) {
+            Some(&LoopMachine::Connection(ref machine)) => Some(machine),
+            _ => None
+        }
+    }
+
+    /// The Connection registered with the given Token, mutably.
+    pub fn connection_mut(&mut self, token: Token) -> Option<&mut IoMachine<Connection>> {
+        match self.slab.get_mut(token) {
+            Some(&mut LoopMachine::Connection(ref mut machine)) => Some(machine),
+            _ => None
+        }
+    }
+
     pub fn deregister<E: Evented>(&mut self, io: &mut IoMachine<E>,
                                   event_loop: &mut EventLoop<Self>,
                                   interest: EventSet)
@@ -100,6 +151,66 @@ impl EventMachine for LoopMachine {
     }
 }
 
+impl LoopMachine {
+    fn token(&self) -> Option<Token> {
+        match *self {
+            LoopMachine::Connection(ref machine) => Some(machine.token),
+            LoopMachine::Acceptor(ref machine) => Some(machine.token),
+            LoopMachine::Active => None
+        }
+    }
+
+    fn is_connection(&self) -> bool {
+        match *self {
+            LoopMachine::Connection(_) => true,
+            _ => false
+        }
+    }
+
+    fn timeout(self, event_loop: &mut EventLoop<LoopHandler>, handler: &mut LoopHandler,
+               generation: usize, timeout: connection::Timeout) -> Option<Self> {
+        match self {
+            LoopMachine::Connection(machine) =>
+                machine.timeout(event_loop, handler, generation, timeout).map(Into::into),
+            LoopMachine::Active =>
+                panic!("Recursive timeout! LoopMachine::timeout called on Active."),
+            machine => {
+                error!("Connection timeout {:?} fired on {:?}", timeout, machine);
+                Some(machine)
+            }
+        }
+    }
+
+    fn drain(self, event_loop: &mut EventLoop<LoopHandler>,
+             handler: &mut LoopHandler) -> Option<Self> {
+        match self {
+            LoopMachine::Connection(machine) =>
+                machine.drain(event_loop, handler).map(Into::into),
+            LoopMachine::Acceptor(mut machine) => {
+                debug!("Closing acceptor {:?} for shutdown.", machine.token);
+                handler.deregister(&mut machine, event_loop, EventSet::all());
+                None
+            },
+            LoopMachine::Active =>
+                panic!("Recursive drain! LoopMachine::drain called on Active.")
+        }
+    }
+
+    fn run(self, event_loop: &mut EventLoop<LoopHandler>, handler: &mut LoopHandler,
+           thunk: connection::Thunk) -> Option<Self> {
+        match self {
+            LoopMachine::Connection(machine) =>
+                machine.run(event_loop, handler, thunk).map(Into::into),
+            LoopMachine::Active =>
+                panic!("Recursive run! LoopMachine::run called on Active."),
+            machine => {
+                error!("Cannot run connection callback on {:?}", machine);
+                Some(machine)
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct IoMachine<I> {
     pub io: I,
@@ -108,6 +219,42 @@ pub struct IoMachine<I> {
     pub pollopt: PollOpt
 }
 
+// A pooled request waiting for a connection to its authority.
+enum Waiting {
+    Request(Request, Complete<Response, Error>),
+    Retry(Retry)
+}
+
+impl Waiting {
+    // Send the request once a connection can take it.
+    fn thunk(self) -> connection::Thunk {
+        match self {
+            Waiting::Request(request, complete) =>
+                Box::new(move |connection: &mut Connection| {
+                    connection.send_pooled(request, complete)
+                }),
+            Waiting::Retry(retry) =>
+                Box::new(move |connection: &mut Connection| connection.resend(retry))
+        }
+    }
+
+    fn fail(self, error: Error) {
+        match self {
+            Waiting::Request(_, complete) => complete.fail(error),
+            Waiting::Retry(retry) => retry.complete.fail(error)
+        }
+    }
+}
+
+impl fmt::Debug for Waiting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Waiting::Request(ref request, _) => write!(f, "Waiting::Request({:?}, ..)", request),
+            Waiting::Retry(ref retry) => write!(f, "Waiting::Retry({:?}, ..)", retry.replay)
+        }
+    }
+}
+
 impl Into<LoopMachine> for IoMachine<Connection> {
     fn into(self) -> LoopMachine { LoopMachine::Connection(self) }
 }
@@ -156,16 +303,188 @@ impl mio::Evented for Acceptor {
      }
 }
 
-impl mio::Handler for LoopHandler {
-    type Message = Message;
-    type Timeout = Thunk<'static>;
+impl LoopHandler {
+    /// Open a client connection to the server at `address`.
+    fn connect(&mut self, event_loop: &mut EventLoop<Self>,
+               address: SocketAddr) -> Result<Token> {
+        let stream = try!(TcpStream::connect(&address));
+
+        let channel = event_loop.channel();
+        let metadata = self.metadata.clone();
+
+        // The connection becomes writable once it is established.
+        let token = self.register_with(
+            event_loop, EventSet::readable() | EventSet::writable() | EventSet::hup(),
+            move |token| Connection::client(stream, metadata, Remote::new(channel, token)));
+
+        if let Some(machine) = self.connection(token) {
+            machine.schedule(event_loop);
+        }
+
+        Ok(token)
+    }
+
+    /// Send a request over a pooled connection to its authority, opening a
+    /// new connection if none can take another stream.
+    fn request(&mut self, event_loop: &mut EventLoop<Self>, request: Request,
+               complete: Complete<Response, Error>) {
+        let authority = match request.authority {
+            Some(ref authority) => pool::with_port(authority, &request.scheme),
+            None => return complete.fail(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput, "request has no authority")))
+        };
+
+        self.budget.deposit();
+        self.pooled(event_loop, authority, None, Waiting::Request(request, complete));
+    }
+
+    /// Send a pooled request the server did not process again, on another
+    /// connection, if the request and the budget allow another retry.
+    fn retry(&mut self, event_loop: &mut EventLoop<Self>, retry: Retry) {
+        if retry.retries > MAX_RETRIES || !self.budget.withdraw() {
+            debug!("Not retrying request {:?}: {}", retry.replay, retry.error);
+            return retry.complete.fail(Error::Http(retry.error))
+        }
+
+        self.requeue(event_loop, retry)
+    }
+
+    /// Send a retry on a connection other than the one it was last given
+    /// to, without counting it as another retry.
+    fn requeue(&mut self, event_loop: &mut EventLoop<Self>, retry: Retry) {
+        if self.draining {
+            debug!("Shutting down, dropping retried request {:?}", retry.replay);
+            return retry.complete.fail(Error::Http(retry.error))
+        }
+
+        let (authority, scheme) = {
+            let fields = retry.replay.fields();
+            let field = |name: &[u8]| {
+                fields.iter()
+                    .find(|field| &*field.name == name)
+                    .and_then(|field| String::from_utf8(field.value.clone()).ok())
+            };
+
+            (field(&b":authority"[..]), field(&b":scheme"[..]))
+        };
+
+        match (authority, scheme) {
+            (Some(authority), Some(scheme)) => {
+                let refused_by = retry.refused_by;
+                self.pooled(event_loop, pool::with_port(&authority, &scheme),
+                            Some(refused_by), Waiting::Retry(retry))
+            },
+            _ => retry.complete.fail(Error::Http(retry.error))
+        }
+    }
+
+    // Send a request over a pooled connection to an authority which can
+    // take another stream, other than `exclude`, opening a new connection
+    // if there is none.
+    fn pooled(&mut self, event_loop: &mut EventLoop<Self>, authority: String,
+              exclude: Option<Token>, waiting: Waiting) {
+        let available = self.pool.get(&authority).iter().cloned().find(|&token| {
+            match self.slab.get(token) {
+                Some(&LoopMachine::Connection(ref machine)) =>
+                    Some(token) != exclude && machine.io.can_send_request(),
+                _ => false
+            }
+        });
+
+        match available {
+            Some(token) => self.transition(event_loop, token, move |machine, event_loop, handler| {
+                machine.run(event_loop, handler, waiting.thunk())
+            }),
+            None => self.resolve(event_loop, authority, waiting)
+        }
+    }
+
+    // Resolve the address of an authority on the executor, so the lookup
+    // does not block the event loop. Requests wait until the connection
+    // to the authority is opened.
+    fn resolve(&mut self, event_loop: &mut EventLoop<Self>, authority: String,
+               waiting: Waiting) {
+        {
+            let waiters = self.resolving.entry(authority.clone()).or_insert_with(Vec::new);
+            waiters.push(waiting);
+
+            if waiters.len() > 1 {
+                return debug!("Already resolving {:?}", authority)
+            }
+        }
+
+        debug!("Resolving {:?}", authority);
+        let channel = event_loop.channel();
+        self.metadata.executor.execute(Box::new(move || {
+            let address = authority.to_socket_addrs().and_then(|mut addresses| {
+                addresses.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "authority did not resolve")
+                })
+            });
+
+            // If the event loop is gone, the waiting requests were aborted.
+            let _ = channel.send(Message::Resolved(authority, address));
+        }));
+    }
+
+    // Open a new pooled connection to a resolved authority, and send the
+    // requests waiting for it.
+    fn resolved(&mut self, event_loop: &mut EventLoop<Self>, authority: String,
+                address: io::Result<SocketAddr>) {
+        let waiting = self.resolving.remove(&authority).unwrap_or_else(Vec::new);
+
+        if self.draining {
+            return debug!("Shutting down, dropping {} pooled requests.", waiting.len())
+        }
+
+        let token = match address {
+            Ok(address) => self.connect(event_loop, address),
+            Err(e) => Err(Error::Io(e))
+        };
+
+        match token {
+            Ok(token) => {
+                debug!("Opened pooled connection {:?} to {:?}", token, authority);
+                if let Some(machine) = self.connection_mut(token) {
+                    machine.schedule_idle(event_loop);
+                }
+
+                self.pool.insert(authority, token);
+
+                for waiting in waiting {
+                    self.transition(event_loop, token, move |machine, event_loop, handler| {
+                        machine.run(event_loop, handler, waiting.thunk())
+                    });
+                }
+            },
+            Err(e) => {
+                error!("Failed to connect to {:?}: {:?}", authority, e);
+                let kind = match e {
+                    Error::Io(ref e) => e.kind(),
+                    _ => io::ErrorKind::Other
+                };
+
+                for waiting in waiting {
+                    waiting.fail(Error::Io(io::Error::new(kind, e.to_string())));
+                }
+            }
+        }
+    }
+
+    /// Transition the machine registered for token, removing it from
+    /// the slab if the transition returns None.
+    fn transition<F>(&mut self, event_loop: &mut EventLoop<Self>, token: Token, action: F)
+    where F: FnOnce(LoopMachine, &mut EventLoop<Self>, &mut Self) -> Option<LoopMachine> {
+        if !self.slab.contains(token) {
+            debug!("No machine registered for token {:?}", token);
+            return
+        }
 
-    fn ready(&mut self, event_loop: &mut EventLoop<Self>, token: Token, events: EventSet) {
         let old_machine = self.slab.replace(token, LoopMachine::Active);
-        debug!("Event {:?} received on token {:?}, machine: {:?}", events, token, old_machine);
+        debug!("Transitioning machine {:?} for token {:?}", old_machine, token);
 
         let new_machine = old_machine
-            .and_then(|machine| machine.ready(event_loop, self, events));
+            .and_then(|machine| action(machine, event_loop, self));
 
         match new_machine {
             Some(machine) => {
@@ -175,11 +494,49 @@ impl mio::Handler for LoopHandler {
             None => {
                 debug!("Deregistering machine from slab with token {:?}", token);
                 self.slab.remove(token);
+                self.pool.remove(token);
             }
         };
 
         trace!("Finished processing event, slab: {:?}",
                self.slab.iter().collect::<Vec<_>>());
+
+        if self.draining && self.slab.iter().all(|machine| !machine.is_connection()) {
+            debug!("All connections drained, shutting down.");
+            event_loop.shutdown();
+        }
+    }
+
+    /// Stop accepting connections, and drain all existing connections.
+    fn shutdown(&mut self, event_loop: &mut EventLoop<Self>, deadline_ms: u64) {
+        self.draining = true;
+
+        let tokens = self.slab.iter().filter_map(LoopMachine::token).collect::<Vec<_>>();
+        if tokens.is_empty() {
+            return event_loop.shutdown()
+        }
+
+        for token in tokens {
+            self.transition(event_loop, token, |machine, event_loop, handler| {
+                machine.drain(event_loop, handler)
+            });
+        }
+
+        if let Err(e) = event_loop.timeout_ms(Timeout::Shutdown, deadline_ms) {
+            error!("Failed to schedule shutdown deadline: {:?}", e);
+        }
+    }
+}
+
+impl mio::Handler for LoopHandler {
+    type Message = Message;
+    type Timeout = Timeout;
+
+    fn ready(&mut self, event_loop: &mut EventLoop<Self>, token: Token, events: EventSet) {
+        debug!("Event {:?} received on token {:?}", events, token);
+        self.transition(event_loop, token, |machine, event_loop, handler| {
+            machine.ready(event_loop, handler, events)
+        });
     }
 
     fn notify(&mut self, event_loop: &mut EventLoop<LoopHandler>,
@@ -187,19 +544,201 @@ impl mio::Handler for LoopHandler {
         debug!("Notify message recieved: {:?}", message);
         match message {
             Message::NextTick(thunk) => thunk(),
+            Message::Listener(_, _) if self.draining =>
+                debug!("Shutting down, ignoring new listener."),
             Message::Listener(listener, handler) => {
                 let metadata = self.metadata.clone();
                 self.register(Acceptor::new(listener, handler, metadata), event_loop,
                               EventSet::readable());
             },
-            Message::Shutdown => event_loop.shutdown(),
-            Message::Timeout(thunk, ms) => { let _ = event_loop.timeout_ms(thunk, ms); }
+            Message::Connection(token, thunk) =>
+                self.transition(event_loop, token, move |machine, event_loop, handler| {
+                    machine.run(event_loop, handler, thunk)
+                }),
+            Message::Connect(address, _) if self.draining =>
+                debug!("Shutting down, not connecting to {:?}.", address),
+            Message::Connect(address, complete) => match self.connect(event_loop, address) {
+                Ok(token) =>
+                    complete.complete(Client::new(Remote::new(event_loop.channel(), token))),
+                Err(e) => {
+                    error!("Failed to connect to {:?}: {:?}", address, e);
+                    complete.fail(e)
+                }
+            },
+            Message::Request(_, _) if self.draining =>
+                debug!("Shutting down, dropping pooled request."),
+            Message::Request(request, complete) => self.request(event_loop, request, complete),
+            Message::Retry(retry) => self.retry(event_loop, retry),
+            Message::Requeue(retry) => self.requeue(event_loop, retry),
+            Message::Resolved(authority, address) =>
+                self.resolved(event_loop, authority, address),
+            Message::Shutdown(deadline_ms) => self.shutdown(event_loop, deadline_ms),
+            Message::Timeout(thunk, ms) => {
+                let _ = event_loop.timeout_ms(Timeout::Thunk(thunk), ms);
+            }
         }
     }
 
     fn timeout(&mut self, event_loop: &mut EventLoop<LoopHandler>,
-               thunk: Thunk<'static>) {
-        thunk()
+               timeout: Timeout) {
+        debug!("Timeout {:?} fired.", timeout);
+        match timeout {
+            Timeout::Thunk(thunk) => thunk(),
+            Timeout::Connection(token, generation, timeout) =>
+                self.transition(event_loop, token, |machine, event_loop, handler| {
+                    machine.timeout(event_loop, handler, generation, timeout)
+                }),
+            Timeout::Shutdown => {
+                error!("Connections did not drain in time, shutting down.");
+                event_loop.shutdown()
+            }
+        }
     }
 }
 
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use mio::{EventLoop, Token};
+
+    use rt::{self, Executor, Thunk};
+    use rt::connection::Timeout;
+    use rt::test::metadata;
+    use message::Request;
+
+    use prelude::*;
+
+    use super::LoopHandler;
+
+    // Counts the lookups started, without running them.
+    struct Lookups(Arc<AtomicUsize>);
+
+    impl Executor for Lookups {
+        fn execute(&self, _: Thunk<'static>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_pooled_connections() {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let mut handler = LoopHandler::new(metadata(Lookups(lookups.clone())));
+        let mut event_loop = EventLoop::new().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let authority = address.to_string();
+
+        let request = |handler: &mut LoopHandler, event_loop: &mut EventLoop<LoopHandler>| {
+            let mut request = Request::new("GET", "/");
+            request.authority = Some(authority.clone());
+
+            let (complete, response) = Future::pair();
+            handler.request(event_loop, request, complete);
+            response
+        };
+
+        // Requests wait for a single lookup of their authority.
+        let _first = request(&mut handler, &mut event_loop);
+        let _second = request(&mut handler, &mut event_loop);
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+        assert_eq!(handler.resolving[&authority].len(), 2);
+
+        handler.resolved(&mut event_loop, authority.clone(), Ok(address));
+        assert!(handler.resolving.is_empty());
+        assert_eq!(handler.pool.get(&authority).len(), 1);
+
+        // Later requests reuse the connection.
+        let _third = request(&mut handler, &mut event_loop);
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+        assert_eq!(handler.pool.get(&authority).len(), 1);
+
+        // Closed connections are evicted, and the authority resolved again.
+        let token = handler.pool.get(&authority)[0];
+        handler.transition(&mut event_loop, token, |_, _, _| None);
+        assert_eq!(handler.pool.get(&authority).len(), 0);
+
+        let failed = request(&mut handler, &mut event_loop);
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+
+        // Requests fail if the authority does not resolve.
+        let error = io::Error::new(io::ErrorKind::InvalidInput, "authority did not resolve");
+        handler.resolved(&mut event_loop, authority.clone(), Err(error));
+        assert!(failed.await().is_err());
+        assert_eq!(handler.pool.get(&authority).len(), 0);
+    }
+
+    #[test]
+    fn test_stale_timeouts() {
+        let mut handler = LoopHandler::new(metadata(Lookups(Arc::new(AtomicUsize::new(0)))));
+        let mut event_loop = EventLoop::new().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let first = handler.connect(&mut event_loop, address).unwrap();
+        let stale = handler.connection(first).unwrap().io.generation();
+        handler.transition(&mut event_loop, first, |_, _, _| None);
+
+        // The slab hands the Token of the removed connection to the next.
+        let token = handler.connect(&mut event_loop, address).unwrap();
+        let current = handler.connection(token).unwrap().io.generation();
+        assert_eq!(token, first);
+
+        // Only the timeouts of the current connection close it.
+        ::mio::Handler::timeout(&mut handler, &mut event_loop,
+                                rt::Timeout::Connection(token, stale, Timeout::Settings));
+        assert!(handler.connection(token).unwrap().io.can_send_request());
+
+        ::mio::Handler::timeout(&mut handler, &mut event_loop,
+                                rt::Timeout::Connection(token, current, Timeout::Settings));
+        assert!(!handler.connection(token).map_or(false, |machine| {
+            machine.io.can_send_request()
+        }));
+    }
+
+    #[test]
+    fn test_idle_timeouts() {
+        let mut handler = LoopHandler::new(metadata(Lookups(Arc::new(AtomicUsize::new(0)))));
+        let mut event_loop = EventLoop::new().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let idle = |handler: &mut LoopHandler, event_loop: &mut EventLoop<LoopHandler>,
+                    token: Token| {
+            let generation = handler.connection(token).unwrap().io.generation();
+            for _ in 0..2 {
+                ::mio::Handler::timeout(handler, event_loop,
+                                        rt::Timeout::Connection(token, generation, Timeout::Idle));
+            }
+            handler.connection(token).map_or(false, |machine| machine.io.can_send_request())
+        };
+
+        // Only pooled connections are closed once idle.
+        let token = handler.connect(&mut event_loop, address).unwrap();
+        assert!(idle(&mut handler, &mut event_loop, token));
+
+        let pooled = handler.connect(&mut event_loop, address).unwrap();
+        handler.connection_mut(pooled).unwrap().schedule_idle(&mut event_loop);
+        assert!(!idle(&mut handler, &mut event_loop, pooled));
+    }
+
+    #[test]
+    fn test_request_without_port() {
+        let mut handler = LoopHandler::new(metadata(Lookups(Arc::new(AtomicUsize::new(0)))));
+        let mut event_loop = EventLoop::new().unwrap();
+
+        let mut request = Request::new("GET", "/");
+        request.authority = Some("example.com".to_string());
+        request.scheme = "https".to_string();
+
+        let (complete, _response) = Future::pair();
+        handler.request(&mut event_loop, request, complete);
+        assert!(handler.resolving.contains_key("example.com:443"));
+    }
+}
@@ -1,4 +1,5 @@
 use rt::Executor;
+use http::{Settings, PaddingPolicy};
 use std::sync::Arc;
 use std::fmt;
 
@@ -8,12 +9,35 @@ use std::fmt;
 /// usually on other threads.
 #[derive(Clone)]
 pub struct Metadata {
-    pub executor: Arc<Box<Executor>>
+    pub executor: Arc<Box<Executor>>,
+
+    /// The settings advertised to peers on new connections.
+    pub settings: Settings,
+
+    /// If set, idle peers are pinged and connections to peers which stop
+    /// responding are closed.
+    pub keepalive: Option<Keepalive>,
+
+    /// How frames sent on new connections are padded.
+    pub padding: PaddingPolicy
+}
+
+/// Keepalive configuration.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Keepalive {
+    /// How often to send a PING.
+    pub interval_ms: u64,
+
+    /// How long the peer has to acknowledge a PING before the connection
+    /// is closed.
+    pub timeout_ms: u64
 }
 
 impl fmt::Debug for Metadata {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str("Metadata { executor: Box<Executor> }")
+        write!(f, "Metadata {{ executor: Box<Executor>, settings: {:?}, keepalive: {:?}, \
+                   padding: {:?} }}",
+               self.settings, self.keepalive, self.padding)
     }
 }
 
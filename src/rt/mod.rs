@@ -1,22 +1,39 @@
-use mio::{self, EventLoop, EventLoopConfig};
+use mio::{self, EventLoop, EventLoopConfig, Token};
 use mio::tcp::TcpListener;
 
 use std::boxed::FnBox;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::io;
 use std::result::Result as StdResult;
 use std::fmt;
 
 use rt::loophandler::LoopHandler;
 
+use eventual::Complete;
+
+use message::{Request, Response};
 use prelude::*;
 use Handler as HttpHandler;
 
 pub type Thunk<'a, A = (), R = ()> = Box<FnBox<A, Output=R> + Send + 'a>;
 
+/// How long connections are given to finish in-flight streams on shutdown.
+pub const SHUTDOWN_DEADLINE_MS: u64 = 30 * 1000;
+
+/// How long a pooled client connection may go without any streams before
+/// it is closed.
+pub const POOL_IDLE_TIMEOUT_MS: u64 = 90 * 1000;
+
 pub mod acceptor;
+pub mod client;
 pub mod connection;
+pub mod pool;
+pub mod retry;
 
-pub use rt::metadata::Metadata;
+pub use rt::client::Client;
+pub use rt::pool::Pool;
+pub use rt::metadata::{Metadata, Keepalive};
 
 pub trait Executor: Send + Sync {
     fn execute(&self, Thunk<'static>);
@@ -43,11 +60,46 @@ pub struct Handle {
     shutdown: Future<(), Error>
 }
 
+/// Timeouts scheduled on the event loop.
+pub enum Timeout {
+    /// Run an arbitrary callback.
+    Thunk(Thunk<'static>),
+
+    /// A timeout for the Connection registered with the given Token, if it
+    /// is still the connection of the given generation.
+    Connection(Token, usize, connection::Timeout),
+
+    /// Connections still draining by now are dropped.
+    Shutdown
+}
+
 pub enum Message {
     NextTick(Thunk<'static>),
     Listener(TcpListener, Arc<Box<HttpHandler>>),
     Timeout(Thunk<'static>, u64),
-    Shutdown
+
+    /// Run a callback on the Connection registered with the given Token.
+    Connection(Token, connection::Thunk),
+
+    /// Open a client connection to the server at the given address.
+    Connect(SocketAddr, Complete<Client, Error>),
+
+    /// Send a request over a pooled connection to its authority.
+    Request(Request, Complete<Response, Error>),
+
+    /// Retry a pooled request the server did not process.
+    Retry(retry::Retry),
+
+    /// Send a retry on another connection, because the connection it was
+    /// given to cannot take another stream.
+    Requeue(retry::Retry),
+
+    /// The address of a pooled authority was resolved on the executor.
+    Resolved(String, io::Result<SocketAddr>),
+
+    /// Drain all connections, then stop the event loop. Connections are
+    /// dropped after the given number of milliseconds.
+    Shutdown(u64)
 }
 
 impl Handle {
@@ -65,8 +117,38 @@ impl Handle {
         self.send(Message::Timeout(Box::new(cb), ms))
     }
 
+    /// Open an HTTP/2 connection to the server at `address`, over which
+    /// requests can be sent.
+    ///
+    /// The Future resolves to a Client as soon as the connection is
+    /// registered with the event loop. Requests sent before the connection
+    /// is established are queued.
+    pub fn connect(&self, address: SocketAddr) -> Result<Future<Client, Error>> {
+        let (tx, rx) = Future::pair();
+        try!(self.send(Message::Connect(address, tx)));
+        Ok(rx)
+    }
+
+    /// A Pool sending requests over connections shared with all other
+    /// Pools of this event loop.
+    pub fn pool(&self) -> Pool {
+        Pool::new(self.channel.clone())
+    }
+
+    /// Gracefully shut down the event loop.
+    ///
+    /// No new connections are accepted, and all connections are sent a
+    /// GOAWAY and closed once their in-flight streams finish. The Future
+    /// resolves once all connections are closed, or `SHUTDOWN_DEADLINE_MS`
+    /// passes.
     pub fn shutdown(self) -> Result<Future<(), Error>> {
-        try!(self.send(Message::Shutdown));
+        self.shutdown_timeout_ms(SHUTDOWN_DEADLINE_MS)
+    }
+
+    /// Gracefully shut down the event loop, dropping any connections which
+    /// have not finished after `ms` milliseconds.
+    pub fn shutdown_timeout_ms(self, ms: u64) -> Result<Future<(), Error>> {
+        try!(self.send(Message::Shutdown(ms)));
         Ok(self.shutdown)
     }
 
@@ -117,7 +199,31 @@ impl fmt::Debug for Message {
             Message::Listener(_, _) => fmt.write_str("Message::Listener(..)"),
             Message::Timeout(_, delay) =>
                 write!(fmt, "Message::Timeout(.., {:?})", delay),
-            Message::Shutdown => fmt.write_str("Message::Shutdown")
+            Message::Connection(token, _) =>
+                write!(fmt, "Message::Connection({:?}, ..)", token),
+            Message::Connect(address, _) =>
+                write!(fmt, "Message::Connect({:?}, ..)", address),
+            Message::Request(ref request, _) =>
+                write!(fmt, "Message::Request({:?}, ..)", request),
+            Message::Retry(ref retry) =>
+                write!(fmt, "Message::Retry({:?}, ..)", retry.replay),
+            Message::Requeue(ref retry) =>
+                write!(fmt, "Message::Requeue({:?}, ..)", retry.replay),
+            Message::Resolved(ref authority, ref address) =>
+                write!(fmt, "Message::Resolved({:?}, {:?})", authority, address),
+            Message::Shutdown(deadline) =>
+                write!(fmt, "Message::Shutdown({:?})", deadline)
+        }
+    }
+}
+
+impl fmt::Debug for Timeout {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Timeout::Thunk(_) => fmt.write_str("Timeout::Thunk(..)"),
+            Timeout::Connection(token, generation, timeout) =>
+                write!(fmt, "Timeout::Connection({:?}, {:?}, {:?})", token, generation, timeout),
+            Timeout::Shutdown => fmt.write_str("Timeout::Shutdown")
         }
     }
 }
@@ -131,3 +237,56 @@ impl fmt::Display for Message {
 mod loophandler;
 mod metadata;
 
+// Fixtures shared by the tests of the runtime.
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::thread;
+
+    use mio::tcp::TcpListener;
+
+    use rt::{Executor, Handle, Metadata, Thunk};
+    use message::{Request, Response};
+
+    use prelude::*;
+
+    /// Runs each task on a new thread.
+    pub struct ThreadExecutor;
+
+    impl Executor for ThreadExecutor {
+        fn execute(&self, task: Thunk<'static>) {
+            thread::spawn(move || task());
+        }
+    }
+
+    /// Responds to every request with an empty 200 response.
+    pub struct NoopHandler;
+
+    impl Handler for NoopHandler {
+        fn handle(&self, _: Request) -> Future<Response, Error> {
+            Future::of(Response::new(200))
+        }
+    }
+
+    /// Metadata with the default settings, running tasks on `executor`.
+    pub fn metadata<E: Executor + 'static>(executor: E) -> Metadata {
+        Metadata {
+            executor: Arc::new(Box::new(executor)),
+            settings: Default::default(),
+            keepalive: None,
+            padding: Default::default()
+        }
+    }
+
+    /// Accept connections on a local port, returning its address.
+    pub fn serve<H: Handler>(handle: &Handle, handler: H) -> SocketAddr {
+        let listener =
+            TcpListener::bind(&SocketAddr::from_str("127.0.0.1:0").unwrap()).unwrap();
+        let address = listener.local_addr().unwrap();
+        handle.register(listener, Arc::new(Box::new(handler))).unwrap();
+        address
+    }
+}
+
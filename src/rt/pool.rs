@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use mio::{self, Token};
+
+use rt::Message;
+
+use message::{Request, Response};
+
+use prelude::*;
+
+/// Sends requests over client connections shared by all requests to the
+/// same authority.
+///
+/// Each `host:port` gets one multiplexed connection, and more only once the
+/// server's SETTINGS_MAX_CONCURRENT_STREAMS is reached. Connections which
+/// receive GOAWAY take no new requests, and connections which stay idle
+/// for `POOL_IDLE_TIMEOUT_MS` are closed. Authorities without a port use the
+/// default port of the request's scheme, and are resolved on the executor
+/// rather than the event loop.
+///
+/// Requests the server did not process, because it refused their stream or
+/// sent GOAWAY before reaching them, are retried on another connection,
+/// within the limits described in `rt::retry`.
+#[derive(Clone)]
+pub struct Pool {
+    channel: mio::Sender<Message>
+}
+
+impl Pool {
+    pub fn new(channel: mio::Sender<Message>) -> Pool {
+        Pool { channel: channel }
+    }
+
+    /// Send a request to the server named by its authority.
+    ///
+    /// The Future fails if the request has no authority, the server resets
+    /// the stream, or the server refuses it and it cannot be retried. It is
+    /// aborted if the connection closes before the response is received.
+    pub fn send_request(&self, request: Request) -> Future<Response, Error> {
+        let (complete, response) = Future::pair();
+
+        // If the event loop is gone, dropping `complete` aborts the response.
+        let _ = self.channel.send(Message::Request(request, complete));
+
+        response
+    }
+}
+
+impl fmt::Debug for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("transfer::rt::Pool")
+    }
+}
+
+/// The `host:port` an authority connects to, with the default port of the
+/// scheme if the authority has none.
+pub fn with_port(authority: &str, scheme: &str) -> String {
+    // The host of an IPv6 address is enclosed in brackets.
+    let host = match authority.rfind(']') {
+        Some(end) => &authority[end..],
+        None => authority
+    };
+
+    if host.contains(':') { return authority.to_string() }
+
+    let port = if scheme == "https" { 443 } else { 80 };
+    format!("{}:{}", authority, port)
+}
+
+/// The pooled client connections registered with an event loop, by the
+/// authority they connect to.
+#[derive(Debug, Default)]
+pub struct Connections {
+    authorities: HashMap<String, Vec<Token>>
+}
+
+impl Connections {
+    pub fn new() -> Connections { Connections::default() }
+
+    /// The connections to an authority, oldest first.
+    pub fn get(&self, authority: &str) -> &[Token] {
+        match self.authorities.get(authority) {
+            Some(tokens) => tokens,
+            None => &[]
+        }
+    }
+
+    pub fn insert(&mut self, authority: String, token: Token) {
+        self.authorities.entry(authority).or_insert_with(Vec::new).push(token);
+    }
+
+    /// Forget a connection which has been closed.
+    pub fn remove(&mut self, token: Token) {
+        let emptied = self.authorities.iter_mut().filter_map(|(authority, tokens)| {
+            tokens.retain(|&pooled| pooled != token);
+            if tokens.is_empty() { Some(authority.clone()) } else { None }
+        }).collect::<Vec<_>>();
+
+        for authority in emptied {
+            self.authorities.remove(&authority);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mio::{EventLoopConfig, Token};
+
+    use rt;
+    use rt::test::{ThreadExecutor, NoopHandler, metadata, serve};
+    use message::Request;
+
+    use prelude::*;
+
+    use super::{Connections, with_port};
+
+    #[test]
+    fn test_with_port() {
+        assert_eq!(with_port("example.com", "https"), "example.com:443");
+        assert_eq!(with_port("example.com", "http"), "example.com:80");
+        assert_eq!(with_port("example.com:8080", "https"), "example.com:8080");
+        assert_eq!(with_port("[::1]", "https"), "[::1]:443");
+        assert_eq!(with_port("[::1]:8080", "http"), "[::1]:8080");
+    }
+
+    #[test]
+    fn test_pooled_requests() {
+        let handle = rt::start(EventLoopConfig::new(), metadata(ThreadExecutor)).unwrap();
+        let authority = serve(&handle, NoopHandler).to_string();
+
+        let pool = handle.pool();
+        let request = || {
+            let mut request = Request::new("GET", "/");
+            request.authority = Some(authority.clone());
+            pool.send_request(request)
+        };
+
+        // Requests sent while the authority is resolved wait for the same
+        // connection, which later requests reuse.
+        let (first, second) = (request(), request());
+        assert_eq!(first.await().unwrap().status, 200);
+        assert_eq!(second.await().unwrap().status, 200);
+        assert_eq!(request().await().unwrap().status, 200);
+
+        handle.shutdown().unwrap().await().unwrap();
+    }
+
+    #[test]
+    fn test_connections() {
+        let mut connections = Connections::new();
+        connections.insert("example.com:443".to_string(), Token(1));
+        connections.insert("example.com:443".to_string(), Token(2));
+        connections.insert("example.org:443".to_string(), Token(3));
+
+        assert_eq!(connections.get("example.com:443"), &[Token(1), Token(2)][..]);
+        assert_eq!(connections.get("example.net:443").len(), 0);
+
+        connections.remove(Token(1));
+        connections.remove(Token(3));
+        assert_eq!(connections.get("example.com:443"), &[Token(2)][..]);
+        assert_eq!(connections.get("example.org:443").len(), 0);
+        assert_eq!(connections.authorities.len(), 1);
+    }
+}
@@ -0,0 +1,194 @@
+//! Retrying pooled requests which the server did not process.
+//!
+//! A request is retried when the server refuses its stream, or sends a
+//! GOAWAY whose last stream is below it, and the whole request was kept as
+//! it was sent. Requests with an idempotent method are kept whatever the
+//! size of their body, while other requests are only kept, and so retried,
+//! if their body is at most `REPLAY_BODY_LIMIT` bytes.
+
+use std::cmp;
+
+use mio::Token;
+
+use eventual::Complete;
+
+use http::hpack::HeaderField;
+use http;
+
+use message::{Request, Response, Headers};
+
+use prelude::*;
+
+/// The most times a single request is retried.
+pub const MAX_RETRIES: u32 = 3;
+
+/// The largest body of a request with a method which is not idempotent
+/// that is kept so that the request can be retried.
+pub const REPLAY_BODY_LIMIT: usize = 64 * 1024;
+
+// Each request adds one to the budget and each retry takes away this much,
+// so retries are limited to a fifth of requests.
+const RETRY_COST: u32 = 5;
+
+// The budget starts with enough for this many retries, and never holds
+// more than `MAX_BALANCE`.
+const INITIAL_RETRIES: u32 = 10;
+const MAX_BALANCE: u32 = 100 * RETRY_COST;
+
+/// A request the server refused without processing it, to be sent again on
+/// another connection.
+pub struct Retry {
+    pub replay: Replay,
+    pub complete: Complete<Response, Error>,
+
+    /// How many times the request has been retried, including this retry.
+    pub retries: u32,
+
+    /// The connection which refused the request, or could not take it, on
+    /// which it is not sent again.
+    pub refused_by: Token,
+
+    /// Why the request was refused, with which it fails if it is not
+    /// retried.
+    pub error: http::Error
+}
+
+/// A copy of a request as it was sent, so that it can be sent again.
+///
+/// ```text
+/// The GOAWAY frame also includes the stream identifier of the last stream that was processed
+/// or might be processed. [...] Requests on streams with higher numbers [...] can be safely
+/// retried.
+/// ```
+#[derive(Debug)]
+pub struct Replay {
+    fields: Vec<HeaderField>,
+    body: Vec<u8>,
+
+    // Does the request have an idempotent method, see Section 4.2.2 of
+    // RFC 7231? Its body is then kept whatever its size.
+    idempotent: bool,
+
+    // The trailers which ended the request, once it has ended.
+    trailers: Option<Headers>
+}
+
+/// Limits retries to a fraction of all requests, so that a struggling
+/// server is not stormed with retries.
+#[derive(Debug)]
+pub struct Budget {
+    balance: u32
+}
+
+impl Replay {
+    pub fn new(request: &Request) -> Replay {
+        Replay {
+            fields: request.fields(),
+            body: Vec::new(),
+            idempotent: is_idempotent(&request.method),
+            trailers: None
+        }
+    }
+
+    /// Keep a chunk of the body as it is sent. Returns false once the body
+    /// is too large to keep.
+    pub fn push(&mut self, chunk: &[u8]) -> bool {
+        if !self.idempotent && self.body.len() + chunk.len() > REPLAY_BODY_LIMIT {
+            return false
+        }
+
+        self.body.extend_from_slice(chunk);
+        true
+    }
+
+    /// Record the end of the request, with its trailers.
+    pub fn end(&mut self, trailers: Headers) {
+        self.trailers = Some(trailers);
+    }
+
+    /// Has the whole request been kept?
+    pub fn is_complete(&self) -> bool { self.trailers.is_some() }
+
+    pub fn fields(&self) -> &[HeaderField] { &self.fields }
+
+    pub fn body(&self) -> &[u8] { &self.body }
+
+    pub fn trailers(&self) -> &[HeaderField] {
+        match self.trailers {
+            Some(ref trailers) => trailers,
+            None => &[]
+        }
+    }
+}
+
+// Methods which can be repeated with the same effect as sending them once.
+fn is_idempotent(method: &str) -> bool {
+    match method {
+        "GET" | "HEAD" | "OPTIONS" | "PUT" | "DELETE" => true,
+        _ => false
+    }
+}
+
+impl Budget {
+    pub fn new() -> Budget {
+        Budget { balance: INITIAL_RETRIES * RETRY_COST }
+    }
+
+    /// Record a new request.
+    pub fn deposit(&mut self) {
+        self.balance = cmp::min(self.balance + 1, MAX_BALANCE);
+    }
+
+    /// Take a retry from the budget, returning false if there is none left.
+    pub fn withdraw(&mut self) -> bool {
+        if self.balance < RETRY_COST { return false }
+        self.balance -= RETRY_COST;
+        true
+    }
+}
+
+impl Default for Budget {
+    fn default() -> Budget { Budget::new() }
+}
+
+#[cfg(test)]
+mod test {
+    use message::Request;
+
+    use super::{Budget, Replay, INITIAL_RETRIES, RETRY_COST, REPLAY_BODY_LIMIT};
+
+    #[test]
+    fn test_budget() {
+        let mut budget = Budget::new();
+        for _ in 0..INITIAL_RETRIES { assert!(budget.withdraw()) }
+        assert!(!budget.withdraw());
+
+        for _ in 0..RETRY_COST - 1 { budget.deposit() }
+        assert!(!budget.withdraw());
+
+        budget.deposit();
+        assert!(budget.withdraw());
+        assert!(!budget.withdraw());
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut replay = Replay::new(&Request::new("POST", "/"));
+        assert!(replay.push(&[1; 1024]));
+        assert!(!replay.is_complete());
+
+        replay.end(Vec::new());
+        assert!(replay.is_complete());
+        assert_eq!(replay.body(), &[1; 1024][..]);
+
+        assert!(!replay.push(&vec![0; REPLAY_BODY_LIMIT]));
+    }
+
+    #[test]
+    fn test_idempotent_replay() {
+        let mut replay = Replay::new(&Request::new("PUT", "/"));
+        assert!(replay.push(&vec![0; REPLAY_BODY_LIMIT]));
+        assert!(replay.push(&[1; 1024]));
+        assert_eq!(replay.body().len(), REPLAY_BODY_LIMIT + 1024);
+    }
+}
@@ -1,7 +1,7 @@
 use std::marker::PhantomData;
 use std::{mem, slice, ops, fmt};
 
-use appendbuf::Slice;
+use appendbuf::{AppendBuf, Slice};
 
 pub struct TypedSlice<T> {
     buf: Slice,
@@ -32,6 +32,9 @@ impl<T> TypedSlice<T> {
     }
 
     pub fn into_slice(self) -> Slice { self.buf }
+
+    /// The raw bytes underlying this slice.
+    pub fn bytes(&self) -> &[u8] { &self.buf }
 }
 
 impl<T> AsRef<[T]> for TypedSlice<T> {
@@ -50,3 +53,10 @@ impl<T> ops::Deref for TypedSlice<T> {
     fn deref(&self) -> &[T] { self.as_ref() }
 }
 
+
+/// Copy some bytes into a new Slice.
+pub fn slice(bytes: &[u8]) -> Slice {
+    let mut buf = AppendBuf::new(bytes.len());
+    buf.fill(bytes);
+    buf.slice()
+}